@@ -80,12 +80,105 @@ impl AABB {
             self.max[2] / 2. + self.min[2] / 2.,
         ]
     }
+    /// The AABB in which this and other overlap or an empty AABB if they are disjoint.
+    pub fn intersection(self, other: AABB) -> AABB {
+        let aabb = AABB {
+            min: [
+                self.min[0].max(other.min[0]),
+                self.min[1].max(other.min[1]),
+                self.min[2].max(other.min[2]),
+            ],
+            max: [
+                self.max[0].min(other.max[0]),
+                self.max[1].min(other.max[1]),
+                self.max[2].min(other.max[2]),
+            ],
+        };
+        if aabb.is_empty() {
+            AABB::empty()
+        } else {
+            aabb
+        }
+    }
+    /// Returns true if the AABB contains no point.
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|axis| {
+            self.min[axis].is_nan() || self.max[axis].is_nan() || self.min[axis] > self.max[axis]
+        })
+    }
+    /// Returns true if other lies completely inside of this AABB.
+    pub fn contains(&self, other: &AABB) -> bool {
+        (0..3).all(|axis| self.min[axis] <= other.min[axis] && self.max[axis] >= other.max[axis])
+    }
     /// Surface area of the AABB.
     pub fn surface_area(&self) -> f32 {
         2. * (self.max[0] - self.min[0]) * (self.max[1] - self.min[1])
             + 2. * (self.max[1] - self.min[1]) * (self.max[2] - self.min[2])
             + 2. * (self.max[0] - self.min[0]) * (self.max[2] - self.min[2])
     }
+    ///
+    /// Slab test of the ray against this AABB.
+    /// Returns the entry and exit distance clipped to the interval [tmin, tmax] or None if the
+    /// ray misses the AABB in that interval.
+    ///
+    /// Zero direction components are handled explicitly, since `0 * inf` would result in NaN when
+    /// the origin lies on a slab plane. The exit distance is enlarged by `2 * gamma(3)` so that
+    /// rounding errors can only produce false hits but never false misses.
+    /// Rays or intervals containing NaNs and empty AABBs never intersect.
+    ///
+    pub fn intersect_ray(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<(f32, f32)> {
+        if tmin.is_nan() || tmax.is_nan() {
+            return None;
+        }
+        if ray.origin.iter().chain(ray.dir.iter()).any(|x| x.is_nan()) {
+            return None;
+        }
+        let mut tmin = tmin;
+        let mut tmax = tmax;
+        for axis in 0..3 {
+            // NaN bounds would be dropped by `max` and `min` below and skip the slab.
+            if self.min[axis].is_nan() || self.max[axis].is_nan() || self.min[axis] > self.max[axis]
+            {
+                return None;
+            }
+            if ray.dir[axis] == 0. {
+                // The ray is parallel to the slab and either always or never inside of it.
+                if ray.origin[axis] < self.min[axis] || ray.origin[axis] > self.max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (self.min[axis] - ray.origin[axis]) * ray.inv_dir[axis];
+            let t1 = (self.max[axis] - ray.origin[axis]) * ray.inv_dir[axis];
+            let (t_near, t_far) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+            tmin = tmin.max(t_near);
+            tmax = tmax.min(t_far * Self::SLAB_TFAR_SCALE);
+        }
+        if tmin <= tmax {
+            Some((tmin, tmax))
+        } else {
+            None
+        }
+    }
+    /// 1 + 2 * gamma(3) with gamma(n) = n * eps / (1 - n * eps), eps = f32::EPSILON / 2.
+    pub const SLAB_TFAR_SCALE: f32 = 1. + 3. * f32::EPSILON;
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Ray {
+    pub origin: [f32; 3],
+    pub dir: [f32; 3],
+    pub inv_dir: [f32; 3],
+}
+
+impl Ray {
+    pub fn new(origin: [f32; 3], dir: [f32; 3]) -> Self {
+        Self {
+            origin,
+            dir,
+            inv_dir: [1. / dir[0], 1. / dir[1], 1. / dir[2]],
+        }
+    }
 }
 
 impl From<[f32; 3]> for AABB{
@@ -98,3 +191,76 @@ impl From<[f32; 3]> for AABB{
     }
 }
 
+impl From<[[f32; 3]; 3]> for AABB {
+    #[inline]
+    fn from(src: [[f32; 3]; 3]) -> Self {
+        AABB::from(src[0]).grow(src[1].into()).grow(src[2].into())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::aabb::*;
+
+    fn unit() -> AABB {
+        AABB {
+            min: [0., 0., 0.],
+            max: [1., 1., 1.],
+        }
+    }
+
+    #[test]
+    fn test_intersect_ray() {
+        let ray = Ray::new([-1., 0.5, 0.5], [1., 0., 0.]);
+        let (t0, t1) = unit().intersect_ray(&ray, 0., f32::INFINITY).unwrap();
+        assert_eq!(t0, 1.);
+        assert!((2. ..2.0001).contains(&t1));
+
+        let ray = Ray::new([-1., 0.5, 0.5], [-1., 0., 0.]);
+        assert!(unit().intersect_ray(&ray, 0., f32::INFINITY).is_none());
+
+        // The interval is clipped by tmax.
+        let ray = Ray::new([-1., 0.5, 0.5], [1., 0., 0.]);
+        assert!(unit().intersect_ray(&ray, 0., 0.5).is_none());
+    }
+
+    #[test]
+    fn test_intersect_ray_zero_dir() {
+        // Origin on the slab planes of the zero components.
+        let ray = Ray::new([-1., 0., 1.], [1., 0., 0.]);
+        assert!(unit().intersect_ray(&ray, 0., f32::INFINITY).is_some());
+        let ray = Ray::new([-1., 0., 1.], [1., -0., -0.]);
+        assert!(unit().intersect_ray(&ray, 0., f32::INFINITY).is_some());
+
+        // Parallel to and outside of the y slab.
+        let ray = Ray::new([-1., 1.5, 0.5], [1., 0., 0.]);
+        assert!(unit().intersect_ray(&ray, 0., f32::INFINITY).is_none());
+
+        // Origin inside with a degenerate direction.
+        let ray = Ray::new([0.5, 0.5, 0.5], [0., 0., 0.]);
+        assert!(unit().intersect_ray(&ray, 0., f32::INFINITY).is_some());
+    }
+
+    #[test]
+    fn test_intersect_ray_nan() {
+        let ray = Ray::new([f32::NAN, 0.5, 0.5], [1., 0., 0.]);
+        assert!(unit().intersect_ray(&ray, 0., f32::INFINITY).is_none());
+        let ray = Ray::new([-1., 0.5, 0.5], [1., f32::NAN, 0.]);
+        assert!(unit().intersect_ray(&ray, 0., f32::INFINITY).is_none());
+        let ray = Ray::new([-1., 0.5, 0.5], [1., 0., 0.]);
+        assert!(unit().intersect_ray(&ray, f32::NAN, f32::NAN).is_none());
+        assert!(AABB::empty().intersect_ray(&ray, 0., f32::INFINITY).is_none());
+
+        // NaN bounds on the axis along the ray and on a parallel one.
+        for (axis, min) in [(0, true), (0, false), (1, true), (2, false)] {
+            let mut aabb = unit();
+            if min {
+                aabb.min[axis] = f32::NAN;
+            } else {
+                aabb.max[axis] = f32::NAN;
+            }
+            assert!(aabb.intersect_ray(&ray, 0., f32::INFINITY).is_none());
+        }
+    }
+}
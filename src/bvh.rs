@@ -1,50 +1,497 @@
 use crate::aabb::*;
+use std::ops::Range;
 
 pub trait BVHNode {
     type ExternIndex: Copy + Clone;
+    /// Largest node index that can be stored in the node.
+    /// Has to be smaller than `MISS_SENTINEL`.
+    const MAX_INDEX: usize = usize::MAX - 1;
+    /// Miss pointer of the nodes after which the ray leaves the tree.
+    const MISS_SENTINEL: usize = usize::MAX;
     fn new_node(aabb: AABB, right: usize, miss: usize) -> Self;
-    fn new_leaf(aabb: AABB, index: Self::ExternIndex, miss: usize) -> Self;
+    /// Creates a leaf referencing the primitives `indices[range]` of the BVH.
+    fn new_leaf(aabb: AABB, range: Range<usize>, miss: usize) -> Self;
     fn set_right(&mut self, right: usize);
     fn set_miss(&mut self, miss: usize);
+    fn set_aabb(&mut self, aabb: AABB);
     fn right(&self) -> usize;
     fn miss(&self) -> usize;
     fn is_leaf(&self) -> bool;
     fn is_node(&self) -> bool;
+    fn aabb(&self) -> AABB;
+    /// The range of primitives in the indices of the BVH referenced by a leaf.
+    /// Only valid if `is_leaf` returns true.
+    fn range(&self) -> Range<usize>;
 }
 
+///
+/// The algorithm used to build the BVH.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStrategy {
+    /// Evaluates the SAH for every possible split along all three axes in O(n log n).
+    /// Gives the best trees but is the slowest to build.
+    FullSweep,
+    /// Sorts the primitives into `bins` buckets along the largest axis of their centroids and
+    /// only evaluates the SAH at the bucket boundaries.
+    Binned { bins: usize },
+    /// Linear BVH sorting the primitives along a morton curve (see `morton_build`).
+    /// Orders of magnitude faster than the SAH builders but gives worse trees.
+    Morton,
+    /// Binned builder that additionally tries spatial splits, which clip triangles at the split
+    /// plane and reference them from both children (see `build_triangles`).
+    /// Only available when building over triangles.
+    Spatial { bins: usize },
+}
+
+impl Default for BuildStrategy {
+    fn default() -> Self {
+        BuildStrategy::Binned { bins: 16 }
+    }
+}
+
+impl std::str::FromStr for BuildStrategy {
+    type Err = String;
+    ///
+    /// Parses strategies as they would be written in a config file:
+    /// `sweep`, `morton` or `binned` and `spatial` with an optional bin count (`binned:8`).
+    ///
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("sweep"), None) => Ok(BuildStrategy::FullSweep),
+            (Some("morton"), None) => Ok(BuildStrategy::Morton),
+            (Some("binned"), None) => Ok(BuildStrategy::default()),
+            (Some("binned"), Some(bins)) => bins
+                .parse()
+                .map(|bins| BuildStrategy::Binned { bins })
+                .map_err(|_| format!("invalid bin count: {}", bins)),
+            (Some("spatial"), None) => Ok(BuildStrategy::Spatial { bins: 16 }),
+            (Some("spatial"), Some(bins)) => bins
+                .parse()
+                .map(|bins| BuildStrategy::Spatial { bins })
+                .map_err(|_| format!("invalid bin count: {}", bins)),
+            _ => Err(format!("unknown build strategy: {}", s)),
+        }
+    }
+}
+
+///
+/// Settings shared by the builders.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct BuildConfig {
+    pub strategy: BuildStrategy,
+    /// Maximum number of primitives in a leaf.
+    /// Nodes with more primitives are always split.
+    pub max_leaf_size: usize,
+    /// Cost of traversing a node relative to `intersection_cost`.
+    pub traversal_cost: f32,
+    /// Cost of intersecting a primitive.
+    pub intersection_cost: f32,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self {
+            strategy: BuildStrategy::default(),
+            max_leaf_size: 4,
+            traversal_cost: 1.,
+            intersection_cost: 1.,
+        }
+    }
+}
+
+impl BuildConfig {
+    ///
+    /// SAH cost of splitting a parent with surface area `p_sa` into two children with `l_n` and
+    /// `r_n` primitives:
+    ///
+    /// C_trav + (SA_L * N_L + SA_R * N_R) / SA_P * C_isect
+    ///
+    pub fn split_cost(&self, p_sa: f32, l_sa: f32, l_n: usize, r_sa: f32, r_n: usize) -> f32 {
+        // Without surface area the children are hit by every ray hitting the parent.
+        let (l_p, r_p) = if p_sa > 0. {
+            (l_sa / p_sa, r_sa / p_sa)
+        } else {
+            (1., 1.)
+        };
+        self.traversal_cost + (l_p * l_n as f32 + r_p * r_n as f32) * self.intersection_cost
+    }
+    ///
+    /// SAH cost of a leaf with `n` primitives: C_isect * N
+    ///
+    pub fn leaf_cost(&self, n: usize) -> f32 {
+        self.intersection_cost * n as f32
+    }
+    /// Returns a copy of the config using the given strategy.
+    pub const fn with(self, strategy: BuildStrategy) -> Self {
+        Self { strategy, ..self }
+    }
+    ///
+    /// Returns true if the primitives should be put into one leaf instead of splitting them into
+    /// two children, by comparing the SAH cost of both options.
+    ///
+    pub fn terminate(&self, p_sa: f32, l_sa: f32, l_n: usize, r_sa: f32, r_n: usize) -> bool {
+        l_n + r_n <= self.max_leaf_size
+            && self.leaf_cost(l_n + r_n) <= self.split_cost(p_sa, l_sa, l_n, r_sa, r_n)
+    }
+}
+
+///
+/// Result of a primitive intersection test, returned by the callbacks passed to the traversal
+/// functions of the BVH.
+///
+pub trait Intersection {
+    /// Distance along the ray at which the primitive was hit.
+    fn t(&self) -> f32;
+}
+
+impl Intersection for f32 {
+    #[inline]
+    fn t(&self) -> f32 {
+        *self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// No primitives were given to the builder.
+    EmptyInput,
+    /// The AABB of the primitive at position `index` in the input contains NaNs or infinities.
+    NonFiniteBounds { index: usize },
+    /// The tree would contain more nodes than the node type is able to index.
+    TooManyPrimitives { count: usize },
+    /// A binned build needs at least 2 bins.
+    InvalidBinCount { bins: usize },
+    /// The strategy needs the triangles of the primitives and not only their AABBs.
+    MissingTriangles,
+    /// The instance at position `index` references a missing BLAS or cannot be inverted.
+    InvalidInstance { index: usize },
+    /// The extern index does not fit into the index type of the shader buffers.
+    IndexOutOfRange { index: usize },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::EmptyInput => write!(f, "cannot build a BVH without primitives"),
+            BuildError::NonFiniteBounds { index } => {
+                write!(f, "primitive {} has non finite bounds", index)
+            }
+            BuildError::TooManyPrimitives { count } => {
+                write!(
+                    f,
+                    "{} primitives exceed the index range of the node type",
+                    count
+                )
+            }
+            BuildError::InvalidBinCount { bins } => {
+                write!(f, "cannot build a binned BVH with {} bins", bins)
+            }
+            BuildError::MissingTriangles => write!(f, "spatial splits need the triangles"),
+            BuildError::InvalidInstance { index } => write!(f, "instance {} is invalid", index),
+            BuildError::IndexOutOfRange { index } => {
+                write!(f, "index {} exceeds the index type of the node", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The tree has no nodes.
+    Empty,
+    /// The left child (index + 1) of the node is out of range.
+    LeftOutOfRange { index: usize },
+    /// The right child of the node is out of range or does not follow the left subtree.
+    InvalidRight { index: usize, right: usize },
+    /// The nodes following the root subtree are not part of the tree.
+    TrailingNodes { end: usize },
+    /// The miss pointer is not the right child of the node's pivot.
+    InvalidMiss {
+        index: usize,
+        miss: usize,
+        expected: usize,
+    },
+    /// The stackless walk did not visit the leaf exactly once.
+    LeafVisits { index: usize, visits: usize },
+    /// The primitive range of the leaf is empty or does not continue the range of the
+    /// previous leaf.
+    InvalidLeafRange { index: usize },
+    /// The leaves do not reference all indices.
+    UnreferencedIndices { start: usize },
+    /// The AABB of the child is not contained in the AABB of its parent.
+    ChildNotContained { parent: usize, child: usize },
+    /// The cached AABB of the tree does not match the AABB of the root node.
+    RootAabbMismatch,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Empty => write!(f, "the BVH has no nodes"),
+            ValidationError::LeftOutOfRange { index } => {
+                write!(f, "left child of node {} is out of range", index)
+            }
+            ValidationError::InvalidRight { index, right } => {
+                write!(f, "node {} has an invalid right child {}", index, right)
+            }
+            ValidationError::TrailingNodes { end } => {
+                write!(f, "nodes after index {} are not part of the tree", end)
+            }
+            ValidationError::InvalidMiss {
+                index,
+                miss,
+                expected,
+            } => write!(
+                f,
+                "node {} has miss pointer {} but {} was expected",
+                index, miss, expected
+            ),
+            ValidationError::LeafVisits { index, visits } => {
+                write!(f, "leaf {} was visited {} times by the walk", index, visits)
+            }
+            ValidationError::InvalidLeafRange { index } => {
+                write!(f, "leaf {} has an invalid primitive range", index)
+            }
+            ValidationError::UnreferencedIndices { start } => {
+                write!(
+                    f,
+                    "indices from {} on are not referenced by any leaf",
+                    start
+                )
+            }
+            ValidationError::ChildNotContained { parent, child } => write!(
+                f,
+                "the aabb of node {} is not contained in its parent {}",
+                child, parent
+            ),
+            ValidationError::RootAabbMismatch => {
+                write!(f, "the aabb of the BVH does not match the root node")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
 ///
 /// TODO: Implement Bucket methode.
 ///
 #[derive(Debug)]
 pub struct BVH<Node: BVHNode> {
     pub nodes: Vec<Node>,
+    /// The extern indices of the primitives, ordered such that every leaf references a
+    /// contiguous range.
+    pub indices: Vec<Node::ExternIndex>,
     aabb: AABB,
 }
 
+///
+/// BVH borrowing its nodes and indices, for example from a memory mapped cache file (see
+/// `BVHView::from_bytes`). It supports the same queries as `BVH`, which implements them by
+/// borrowing itself with `BVH::view`.
+///
+#[derive(Debug)]
+pub struct BVHView<'a, Node: BVHNode> {
+    nodes: &'a [Node],
+    indices: &'a [Node::ExternIndex],
+    aabb: AABB,
+}
+
+///
+/// Buckets and accumulated bounds used by the binned builder, allocated once per build.
+///
+pub(crate) struct BinScratch<Index> {
+    buckets: Vec<Vec<IndexedAABB<Index>>>,
+    bucket_aabbs: Vec<AABB>,
+    l_bucket_aabb_acc: Vec<AABB>,
+    r_bucket_aabb_acc: Vec<AABB>,
+}
+
+impl<Index: Copy> BinScratch<Index> {
+    pub(crate) fn new(bins: usize) -> Self {
+        Self {
+            buckets: vec![Vec::new(); bins],
+            bucket_aabbs: vec![AABB::empty(); bins],
+            l_bucket_aabb_acc: vec![AABB::empty(); bins],
+            r_bucket_aabb_acc: vec![AABB::empty(); bins],
+        }
+    }
+}
+
+///
+/// The children sorted by their centroid along every axis, used by the full sweep builder.
+/// Ties are broken by the index of the child so the lists are consistent with each other.
+///
+struct SweepScratch {
+    lists: [Vec<usize>; 3],
+    r_aabbs: Vec<AABB>,
+    left: Vec<bool>,
+    tmp: Vec<usize>,
+}
+
+impl SweepScratch {
+    fn new<Index>(children: &[IndexedAABB<Index>]) -> Self {
+        let centroids: Vec<[f32; 3]> = children.iter().map(|c| c.aabb.centroid()).collect();
+        let lists = [0, 1, 2].map(|axis| {
+            let mut list: Vec<usize> = (0..children.len()).collect();
+            list.sort_by(|&a, &b| {
+                centroids[a][axis]
+                    .total_cmp(&centroids[b][axis])
+                    .then(a.cmp(&b))
+            });
+            list
+        });
+        Self {
+            lists,
+            r_aabbs: vec![AABB::empty(); children.len()],
+            left: vec![false; children.len()],
+            tmp: Vec::with_capacity(children.len()),
+        }
+    }
+}
+
 impl<Node: BVHNode> BVH<Node> {
-    pub fn build_sweep<Item: Into<IndexedAABB<Node::ExternIndex>>, I: Iterator<Item = Item>>(
+    ///
+    /// Builds the BVH over the primitives using the strategy and settings of the config.
+    ///
+    pub fn build<Item: Into<IndexedAABB<Node::ExternIndex>>, I: Iterator<Item = Item>>(
         iter: I,
-    ) -> Self {
+        config: &BuildConfig,
+    ) -> Result<Self, BuildError> {
         let mut children: Vec<IndexedAABB<Node::ExternIndex>> = iter.map(|x| x.into()).collect();
-        let aabb = children
-            .iter()
-            .map(|c| c.aabb)
-            .fold(children[0].aabb, AABB::grow);
+        let aabb = Self::check_children(&children)?;
         let mut nodes: Vec<Node> = Vec::new();
-        Self::sweep_pivot(&mut nodes, aabb, &mut children, 0);
-        let mut tree = Self { nodes, aabb};
+        match config.strategy {
+            BuildStrategy::FullSweep => {
+                let mut scratch = SweepScratch::new(&children);
+                Self::sweep_pivot(
+                    &mut nodes,
+                    config,
+                    aabb,
+                    &children,
+                    &mut scratch,
+                    0..children.len(),
+                    0,
+                );
+                children = scratch.lists[0].iter().map(|&i| children[i]).collect();
+            }
+            BuildStrategy::Binned { bins } => {
+                if bins < 2 {
+                    return Err(BuildError::InvalidBinCount { bins });
+                }
+                let mut scratch = BinScratch::new(bins);
+                Self::buckets_pivot(&mut nodes, config, aabb, &mut children, 0, &mut scratch, 0);
+            }
+            BuildStrategy::Morton => {
+                children = Self::morton_build(&mut nodes, config, &children);
+            }
+            BuildStrategy::Spatial { .. } => return Err(BuildError::MissingTriangles),
+        }
+        Ok(Self::from_pivot_tree(nodes, &children))
+    }
+    ///
+    /// Creates the BVH from the nodes generated by a builder and the children in the order in
+    /// which the builder left them.
+    ///
+    pub(crate) fn from_pivot_tree(
+        nodes: Vec<Node>,
+        children: &[IndexedAABB<Node::ExternIndex>],
+    ) -> Self {
+        let indices = children.iter().map(|c| c.index).collect();
+        let mut tree = Self {
+            nodes,
+            indices,
+            aabb: AABB::empty(),
+        };
         Self::pivot_to_miss(&mut tree);
+        tree.enclose_children();
+        debug_assert_eq!(tree.validate(), Ok(()));
         tree
     }
     ///
+    /// Creates the BVH from the nodes and indices of a previous build, for example loaded from
+    /// a cache. The nodes are not checked, use `validate` for that.
+    ///
+    pub fn from_raw_parts(nodes: Vec<Node>, indices: Vec<Node::ExternIndex>) -> Self {
+        let aabb = nodes.first().map_or(AABB::empty(), |root| root.aabb());
+        Self {
+            nodes,
+            indices,
+            aabb,
+        }
+    }
+    ///
+    /// Grows the nodes whose AABB does not contain the AABBs of their children and sets the AABB
+    /// of the BVH to the one of the root.
+    /// This only changes node types that round their bounds up (see `CompactBVHNode`), where a
+    /// child can stick out of its parent by the rounding error.
+    ///
+    pub(crate) fn enclose_children(&mut self) {
+        for i in (0..self.nodes.len()).rev() {
+            if self.nodes[i].is_node() {
+                let right = self.nodes[i].right();
+                let aabb = self.nodes[i]
+                    .aabb()
+                    .grow(self.nodes[i + 1].aabb())
+                    .grow(self.nodes[right].aabb());
+                if !self.nodes[i].aabb().contains(&aabb) {
+                    self.nodes[i].set_aabb(aabb);
+                }
+            }
+        }
+        self.aabb = self.nodes[0].aabb();
+    }
+    ///
+    /// Checks that the input of a builder can be turned into a tree and returns the AABB
+    /// enclosing all children.
+    ///
+    pub(crate) fn check_children(
+        children: &[IndexedAABB<Node::ExternIndex>],
+    ) -> Result<AABB, BuildError> {
+        if children.is_empty() {
+            return Err(BuildError::EmptyInput);
+        }
+        // A binary tree with n leaves has 2n - 1 nodes.
+        if children.len() > Node::MAX_INDEX / 2 {
+            return Err(BuildError::TooManyPrimitives {
+                count: children.len(),
+            });
+        }
+        for (index, child) in children.iter().enumerate() {
+            if child
+                .aabb
+                .min
+                .iter()
+                .chain(child.aabb.max.iter())
+                .any(|x| !x.is_finite())
+            {
+                return Err(BuildError::NonFiniteBounds { index });
+            }
+        }
+        Ok(children
+            .iter()
+            .map(|c| c.aabb)
+            .fold(AABB::empty(), AABB::grow))
+    }
+    ///
     /// Generates the BVH into the dst vector with the `miss` parameter being the pivot of that
     /// node.
     /// * `dst` vector into which the BVH is generate.
+    /// * `config` the settings deciding when to create a leaf.
     /// * `p_aabb` the aabb of the parent.
-    /// * `children` the children of the parent who are split into two parts.
+    /// * `children` all children of the tree.
+    /// * `scratch` the children sorted along every axis. The children of the parent are
+    ///   `lists[axis][range]`, and are partitioned in place. After the build every list holds the
+    ///   children in the order in which the leaves reference them.
+    /// * `range` the range of the lists that is split into two parts.
     /// * `pivot` the pivot of the parent node. The pivot of a node is the parent of the first
-    /// parent, that is a left node.
-    /// It can also be thought of as the lowest common ancestor of the tree.
+    ///   parent, that is a left node.
+    ///   It can also be thought of as the lowest common ancestor of the tree.
     ///
     ///```text
     ///             0
@@ -70,117 +517,126 @@ impl<Node: BVHNode> BVH<Node> {
     ///
     fn sweep_pivot(
         dst: &mut Vec<Node>,
+        config: &BuildConfig,
         p_aabb: AABB,
-        children: &mut [IndexedAABB<Node::ExternIndex>],
+        children: &[IndexedAABB<Node::ExternIndex>],
+        scratch: &mut SweepScratch,
+        range: Range<usize>,
         pivot: usize,
     ) -> usize {
-        let (split_axis, split_axis_size) = p_aabb.largest_axis_with_size();
-
-        // Order the children along the longest axis.
-        // TODO: Implementation with 3 sorted lists.
-        // as described here: https://graphics.cg.uni-saarland.de/courses/cg1-2018/slides/Building_good_BVHs.pdf
-        match split_axis {
-            Axis::X => children.sort_by(|a, b| {
-                a.aabb.centroid()[0]
-                    .partial_cmp(&b.aabb.centroid()[0])
-                    .unwrap()
-            }),
-            Axis::Y => children.sort_by(|a, b| {
-                a.aabb.centroid()[1]
-                    .partial_cmp(&b.aabb.centroid()[1])
-                    .unwrap()
-            }),
-            Axis::Z => children.sort_by(|a, b| {
-                a.aabb.centroid()[2]
-                    .partial_cmp(&b.aabb.centroid()[2])
-                    .unwrap()
-            }),
+        let n = range.len();
+        if n == 1 {
+            dst.push(Node::new_leaf(p_aabb, range, pivot));
+            return dst.len() - 1;
         }
 
-        if children.len() == 1 {
-            dst.push(Node::new_leaf(p_aabb, children[0].index, pivot));
-            dst.len() - 1
-        } else {
-            //println!("{:?}", p_aabb);
-            let mut min_sah = std::f32::MAX;
-            let mut min_sah_idx = 0;
-            let mut min_sah_l_aabb = children[0].aabb;
-            let mut min_sah_r_aabb = AABB::default();
-            let p_sa = p_aabb.surface_area();
-
-            for i in 0..(children.len() - 1) {
-                // The left aabb can be grown with the iteration
-                let l_aabb = min_sah_l_aabb.grow(children[i].aabb);
-                let l_sa = l_aabb.surface_area();
+        // Sweep over the children along every axis and evaluate the SAH for every split.
+        // The right aabbs are accumulated from the back first, so each split is O(1).
+        // See: https://graphics.cg.uni-saarland.de/courses/cg1-2018/slides/Building_good_BVHs.pdf
+        let p_sa = p_aabb.surface_area();
+        let mut min_sah = std::f32::INFINITY;
+        let mut min_sah_axis = 0;
+        let mut min_sah_split = 1;
+        let mut min_sah_l_aabb = AABB::empty();
+        let mut min_sah_r_aabb = AABB::empty();
+        for axis in 0..3 {
+            let list = &scratch.lists[axis][range.clone()];
 
-                // The right aabb has to be generated for each iteration.
-                // This should always at least iterate over the last leaf node.
-                let r_aabb = ((i + 1)..children.len())
-                    .map(|i| children[i].aabb)
-                    .fold(children[i + 1].aabb, AABB::grow);
-                let r_sa = r_aabb.surface_area();
+            // r_aabbs[i] is the aabb of list[i..n].
+            let mut r_aabb = AABB::empty();
+            for i in (1..n).rev() {
+                r_aabb = r_aabb.grow(children[list[i]].aabb);
+                scratch.r_aabbs[i] = r_aabb;
+            }
 
-                let sah = (l_sa + r_sa) / p_sa;
+            let mut l_aabb = AABB::empty();
+            for i in 1..n {
+                // The left aabb can be grown with the iteration
+                l_aabb = l_aabb.grow(children[list[i - 1]].aabb);
+                let r_aabb = scratch.r_aabbs[i];
+                let sah =
+                    config.split_cost(p_sa, l_aabb.surface_area(), i, r_aabb.surface_area(), n - i);
                 if sah < min_sah {
                     min_sah = sah;
-                    min_sah_idx = i;
+                    min_sah_axis = axis;
+                    min_sah_split = i;
                     min_sah_l_aabb = l_aabb;
                     min_sah_r_aabb = r_aabb;
                 }
             }
-            let (l_children, r_children) = children.split_at_mut(min_sah_idx + 1);
-            let node_i = dst.len();
-            dst.push(Node::new_node(p_aabb, 0, pivot));
-            let _l_node_i = Self::sweep_pivot(dst, min_sah_l_aabb, l_children, node_i);
-            let r_node_i = Self::sweep_pivot(dst, min_sah_r_aabb, r_children, pivot);
-            dst[node_i].set_right(r_node_i);
-            //dst[node_i].right = r_node_i as u32;
-            //dst[node_i].miss = pivot as u32;
-            node_i
         }
-    }
-    pub fn build_buckets_8<Item: Into<IndexedAABB<Node::ExternIndex>>, I: Iterator<Item = Item>>(
-        iter: I,
-    ) -> Self {
-        Self::build_buckets_num::<8, Item, I>(iter)
-    }
-    pub fn build_buckets_16<
-        Item: Into<IndexedAABB<Node::ExternIndex>>,
-        I: Iterator<Item = Item>,
-    >(
-        iter: I,
-    ) -> Self {
-        Self::build_buckets_num::<16, Item, I>(iter)
-    }
-    pub fn build_buckets_num<
-        const N: usize,
-        Item: Into<IndexedAABB<Node::ExternIndex>>,
-        I: Iterator<Item = Item>,
-    >(
-        iter: I,
-    ) -> Self {
-        let mut children: Vec<IndexedAABB<Node::ExternIndex>> = iter.map(|x| x.into()).collect();
-        let aabb = children
+
+        let l_n = min_sah_split;
+        let r_n = n - l_n;
+        if config.terminate(
+            p_sa,
+            min_sah_l_aabb.surface_area(),
+            l_n,
+            min_sah_r_aabb.surface_area(),
+            r_n,
+        ) {
+            dst.push(Node::new_leaf(p_aabb, range, pivot));
+            return dst.len() - 1;
+        }
+
+        // Partition the other two lists such that the children on the left side of the split
+        // come first, while keeping them sorted.
+        for (i, &child) in scratch.lists[min_sah_axis][range.clone()]
             .iter()
-            .map(|c| c.aabb)
-            .fold(children[0].aabb, AABB::grow);
-        let mut nodes: Vec<Node> = Vec::new();
-        let mut buckets = vec![Vec::new(); N];
-        Self::buckets_pivot::<N>(&mut nodes, aabb, &mut children, &mut buckets, 0);
-        let mut tree = Self { nodes, aabb };
-        Self::pivot_to_miss(&mut tree);
-        tree
+            .enumerate()
+        {
+            scratch.left[child] = i < l_n;
+        }
+        for axis in 0..3 {
+            if axis != min_sah_axis {
+                let SweepScratch {
+                    lists, left, tmp, ..
+                } = scratch;
+                let list = &mut lists[axis][range.clone()];
+                tmp.clear();
+                tmp.extend(list.iter().filter(|&&child| left[child]));
+                tmp.extend(list.iter().filter(|&&child| !left[child]));
+                list.copy_from_slice(tmp);
+            }
+        }
+
+        let split = range.start + l_n;
+        let node_i = dst.len();
+        dst.push(Node::new_node(p_aabb, 0, pivot));
+        let _l_node_i = Self::sweep_pivot(
+            dst,
+            config,
+            min_sah_l_aabb,
+            children,
+            scratch,
+            range.start..split,
+            node_i,
+        );
+        let r_node_i = Self::sweep_pivot(
+            dst,
+            config,
+            min_sah_r_aabb,
+            children,
+            scratch,
+            split..range.end,
+            pivot,
+        );
+        dst[node_i].set_right(r_node_i);
+        node_i
     }
     ///
     /// Same as sweep_pivot but with Buckets to speed up construction.
     /// Generates the BVH into the dst vector with the `miss` parameter being the pivot of that
     /// node.
     /// * `dst` vector into which the BVH is generate.
+    /// * `config` the settings deciding when to create a leaf.
     /// * `p_aabb` the aabb of the parent.
     /// * `children` the children of the parent who are split into two parts.
+    /// * `offset` the index of the first child in the children of the whole tree.
+    /// * `scratch` the buckets reused by every node.
     /// * `pivot` the pivot of the parent node. The pivot of a node is the parent of the first
-    /// parent, that is a left node.
-    /// It can also be thought of as the lowest common ancestor of the tree.
+    ///   parent, that is a left node.
+    ///   It can also be thought of as the lowest common ancestor of the tree.
     ///
     ///```text
     ///             0
@@ -204,36 +660,24 @@ impl<Node: BVHNode> BVH<Node> {
     ///
     /// The miss pointer of any node is then just the right pointer of its pivot.
     ///
-    fn buckets_pivot<const N: usize>(
+    pub(crate) fn buckets_pivot(
         dst: &mut Vec<Node>,
+        config: &BuildConfig,
         p_aabb: AABB,
         children: &mut [IndexedAABB<Node::ExternIndex>],
-        buckets: &mut Vec<Vec<IndexedAABB<Node::ExternIndex>>>,
+        offset: usize,
+        scratch: &mut BinScratch<Node::ExternIndex>,
         pivot: usize,
     ) -> usize {
         if children.len() == 1 {
-            dst.push(Node::new_leaf(p_aabb, children[0].index, pivot));
-            dst.len() - 1
-        } else if children.len() == 2 {
+            dst.push(Node::new_leaf(p_aabb, offset..(offset + 1), pivot));
+            return dst.len() - 1;
+        }
+
+        let (children_split, l_abb, r_abb) = if children.len() == 2 {
             // Fast track if there are only two children left.
-            //
-            let l_aabb = children[0].aabb;
-            let r_aabb = children[1].aabb;
-
-            let (l_children, r_children) = children.split_at_mut(1);
-            let node_i = dst.len();
-            dst.push(Node::new_node(p_aabb, 0, pivot));
-            let _l_node_i = Self::buckets_pivot::<N>(dst, l_aabb, l_children, buckets, node_i);
-            let r_node_i = Self::buckets_pivot::<N>(dst, r_aabb, r_children, buckets, pivot);
-            dst[node_i].set_right(r_node_i);
-            node_i
+            (1, children[0].aabb, children[1].aabb)
         } else {
-            // clear all buckets.
-            // The buckets Vecs should not have any performance overhead since they probably
-            // decreace in size only.
-            for bucket in buckets.iter_mut() {
-                bucket.clear();
-            }
             let centoid_aabb: AABB = children
                 .iter()
                 .map(|c| c.aabb.centroid().into())
@@ -242,91 +686,213 @@ impl<Node: BVHNode> BVH<Node> {
             let (axis, split_axis_size) = centoid_aabb.largest_axis_with_size();
             let axis: usize = axis.into();
 
-            let mut bucket_aabbs = [AABB::empty(); N];
-
-            // Push the children into their respective buckets.
-            for child in children.iter() {
-                // The bucket number to which to push the child.
-                // a      c        b
-                // [   |   |   |   ]
-                // n = ceil((c-a)/(b-a) * N) -1
-                // TODO: safeguard for division.
-                let n = (((child.aabb.centroid()[axis] - centoid_aabb.min[axis]) / split_axis_size
-                    * (N as f32))
-                    .ceil()
-                    - 1.) as usize;
-                // Insert child into bucket.
-                buckets[n].push(*child);
-                // Grow the aabb corresponding to that bucket.
-                bucket_aabbs[n] = bucket_aabbs[n].grow(child.aabb);
-            }
-
-            // Accumulate the bounding boxes of the buffers for the left and right side. This gives
-            // linear speed.
-            let mut l_bucket_aabb_acc = [AABB::empty(); N];
-            let mut r_bucket_aabb_acc = [AABB::empty(); N];
-            let mut l_aabb = AABB::empty();
-            let mut r_aabb = AABB::empty();
-            for i in 0..(N - 1) {
-                l_aabb = l_aabb.grow(bucket_aabbs[i]);
-                r_aabb = r_aabb.grow(bucket_aabbs[N - i - 1]);
-                l_bucket_aabb_acc[i] = l_aabb;
-                r_bucket_aabb_acc[i] = r_aabb;
-            }
-
-            // Find the bucket after which we should split.
-            let mut min_sah = std::f32::INFINITY;
-            let mut bucket_split = 0;
-            let mut count_non_empty = 0;
-            let p_sa = p_aabb.surface_area();
-            for i in 0..(N - 1) {
-                if !(buckets[i].is_empty()) {
-                    let l_sa = l_bucket_aabb_acc[i].surface_area();
-                    let r_sa = r_bucket_aabb_acc[i].surface_area();
-                    let sah = (l_sa + r_sa) / p_sa;
-                    if sah < min_sah {
-                        min_sah = sah;
-                        bucket_split = i;
-                    }
-                    count_non_empty += 1;
-                }
+            if split_axis_size > 0. {
+                Self::buckets_split(
+                    config,
+                    p_aabb,
+                    children,
+                    scratch,
+                    centoid_aabb,
+                    axis,
+                    split_axis_size,
+                )
+            } else {
+                Self::half_split(children)
             }
+        };
 
-            // Extract the aabbs of the left and right children.
-            let l_abb = l_bucket_aabb_acc[bucket_split];
-            let r_abb = r_bucket_aabb_acc[bucket_split];
+        if config.terminate(
+            p_aabb.surface_area(),
+            l_abb.surface_area(),
+            children_split,
+            r_abb.surface_area(),
+            children.len() - children_split,
+        ) {
+            dst.push(Node::new_leaf(
+                p_aabb,
+                offset..(offset + children.len()),
+                pivot,
+            ));
+            return dst.len() - 1;
+        }
 
-            // Fill children back from bucket into children slice.
-            let mut child_index = 0;
-            let mut children_split = 0;
-            for i in 0..N {
-                for child in buckets[i].iter() {
-                    children[child_index] = *child;
-                    child_index += 1;
-                }
-                // When we have filled the children of the left buckets we keep the child_index;
-                if i == bucket_split {
-                    children_split = child_index;
-                }
-            }
+        // Split the children at the children_split index.
+        let (l_children, r_children) = children.split_at_mut(children_split);
+        let node_i = dst.len();
+        dst.push(Node::new_node(p_aabb, 0, pivot));
+        let _l_node_i =
+            Self::buckets_pivot(dst, config, l_abb, l_children, offset, scratch, node_i);
+        let r_node_i = Self::buckets_pivot(
+            dst,
+            config,
+            r_abb,
+            r_children,
+            offset + children_split,
+            scratch,
+            pivot,
+        );
+        dst[node_i].set_right(r_node_i);
+        //dst[node_i].right = r_node_i as u32;
+        //dst[node_i].miss = pivot as u32;
+        node_i
+    }
+    ///
+    /// If all centroids are in the same place they cannot be separated by the buckets, so we
+    /// just split them in 2.
+    ///
+    pub(crate) fn half_split(children: &[IndexedAABB<Node::ExternIndex>]) -> (usize, AABB, AABB) {
+        let children_split = children.len() / 2;
+        let l_abb = children[..children_split]
+            .iter()
+            .map(|c| c.aabb)
+            .fold(AABB::empty(), AABB::grow);
+        let r_abb = children[children_split..]
+            .iter()
+            .map(|c| c.aabb)
+            .fold(AABB::empty(), AABB::grow);
+        (children_split, l_abb, r_abb)
+    }
+    ///
+    /// Sorts the children into the buckets of the scratch along the axis of the centroid aabb
+    /// and reorders them such that they can be split at the bucket with the lowest SAH cost.
+    /// `split_axis_size` has to be greater than 0.
+    ///
+    /// Returns the index at which to split the children and the aabbs of the two parts.
+    ///
+    pub(crate) fn buckets_split(
+        config: &BuildConfig,
+        p_aabb: AABB,
+        children: &mut [IndexedAABB<Node::ExternIndex>],
+        scratch: &mut BinScratch<Node::ExternIndex>,
+        centoid_aabb: AABB,
+        axis: usize,
+        split_axis_size: f32,
+    ) -> (usize, AABB, AABB) {
+        let n_bins = scratch.buckets.len();
+        let BinScratch {
+            buckets,
+            bucket_aabbs,
+            l_bucket_aabb_acc,
+            r_bucket_aabb_acc,
+        } = scratch;
+
+        // clear all buckets.
+        // The buckets Vecs should not have any performance overhead since they probably
+        // decreace in size only.
+        for bucket in buckets.iter_mut() {
+            bucket.clear();
+        }
+        bucket_aabbs.fill(AABB::empty());
 
-            // If there should only be one bucket occupied (which could happen if all centroids are
-            // in the sampe place) we just split them in 2.
-            if count_non_empty == 1 {
-                children_split = children.len() / 2;
+        // Push the children into their respective buckets.
+        for child in children.iter() {
+            let n = Self::bucket_index(child, centoid_aabb, axis, split_axis_size, n_bins);
+            // Insert child into bucket.
+            buckets[n].push(*child);
+            // Grow the aabb corresponding to that bucket.
+            bucket_aabbs[n] = bucket_aabbs[n].grow(child.aabb);
+        }
+
+        let bucket_split = Self::best_bucket_split(
+            config,
+            p_aabb,
+            children.len(),
+            bucket_aabbs,
+            |i| buckets[i].len(),
+            l_bucket_aabb_acc,
+            r_bucket_aabb_acc,
+        );
+
+        // Fill children back from bucket into children slice.
+        let mut child_index = 0;
+        let mut children_split = 0;
+        for i in 0..n_bins {
+            for child in buckets[i].iter() {
+                children[child_index] = *child;
+                child_index += 1;
             }
+            // When we have filled the children of the left buckets we keep the child_index;
+            if i == bucket_split {
+                children_split = child_index;
+            }
+        }
 
-            // Split the children at the children_split index.
-            let (l_children, r_children) = children.split_at_mut(children_split);
-            let node_i = dst.len();
-            dst.push(Node::new_node(p_aabb, 0, pivot));
-            let _l_node_i = Self::buckets_pivot::<N>(dst, l_abb, l_children, buckets, node_i);
-            let r_node_i = Self::buckets_pivot::<N>(dst, r_abb, r_children, buckets, pivot);
-            dst[node_i].set_right(r_node_i);
-            //dst[node_i].right = r_node_i as u32;
-            //dst[node_i].miss = pivot as u32;
-            node_i
+        (
+            children_split,
+            l_bucket_aabb_acc[bucket_split],
+            r_bucket_aabb_acc[bucket_split],
+        )
+    }
+    ///
+    /// The bucket number to which the child belongs when binning along the axis of the centroid
+    /// aabb.
+    ///
+    #[inline]
+    pub(crate) fn bucket_index(
+        child: &IndexedAABB<Node::ExternIndex>,
+        centoid_aabb: AABB,
+        axis: usize,
+        split_axis_size: f32,
+        n_bins: usize,
+    ) -> usize {
+        // a      c        b
+        // [   |   |   |   ]
+        // n = ceil((c-a)/(b-a) * N) -1
+        // The child with the smallest centroid results in -1 which saturates to 0.
+        (((child.aabb.centroid()[axis] - centoid_aabb.min[axis]) / split_axis_size
+            * (n_bins as f32))
+            .ceil()
+            - 1.) as usize
+    }
+    ///
+    /// Returns the bucket after which to split a node with `n` children, given the aabbs and
+    /// number of children of every bucket.
+    /// Fills the accumulators with the aabbs of the left and right side for every split.
+    ///
+    pub(crate) fn best_bucket_split(
+        config: &BuildConfig,
+        p_aabb: AABB,
+        n: usize,
+        bucket_aabbs: &[AABB],
+        bucket_len: impl Fn(usize) -> usize,
+        l_bucket_aabb_acc: &mut [AABB],
+        r_bucket_aabb_acc: &mut [AABB],
+    ) -> usize {
+        let n_bins = bucket_aabbs.len();
+        // Accumulate the bounding boxes of the buffers for the left and right side. This gives
+        // linear speed.
+        // l_bucket_aabb_acc[i] and r_bucket_aabb_acc[i] hold the aabbs of the left and right
+        // side when splitting after bucket i.
+        let mut l_aabb = AABB::empty();
+        let mut r_aabb = AABB::empty();
+        for i in 0..(n_bins - 1) {
+            l_aabb = l_aabb.grow(bucket_aabbs[i]);
+            r_aabb = r_aabb.grow(bucket_aabbs[n_bins - i - 1]);
+            l_bucket_aabb_acc[i] = l_aabb;
+            r_bucket_aabb_acc[n_bins - i - 2] = r_aabb;
+        }
+
+        // Find the bucket after which we should split.
+        // Since the first and last bucket always contain the children with the smallest and
+        // largest centroid, both sides of a split after a non empty bucket are non empty.
+        let mut min_sah = std::f32::INFINITY;
+        let mut bucket_split = 0;
+        let p_sa = p_aabb.surface_area();
+        let mut l_n = 0;
+        for i in 0..(n_bins - 1) {
+            let len = bucket_len(i);
+            l_n += len;
+            if len != 0 {
+                let l_sa = l_bucket_aabb_acc[i].surface_area();
+                let r_sa = r_bucket_aabb_acc[i].surface_area();
+                let sah = config.split_cost(p_sa, l_sa, l_n, r_sa, n - l_n);
+                if sah < min_sah {
+                    min_sah = sah;
+                    bucket_split = i;
+                }
+            }
         }
+        bucket_split
     }
     ///
     /// Change the pivot stored in the miss "pointer" to the miss pointer by setting it to the
@@ -337,18 +903,28 @@ impl<Node: BVHNode> BVH<Node> {
         for i in 0..self.nodes.len() {
             if i >= self.nodes[0].right() && self.nodes[i].miss() == 0 {
                 // The right most node's pivot would be the root node.
-                // To invalidate their misses and indicate a miss of the whole tree they are set to
-                // 0.
-                // The root node (0) cannot be a miss.
-                // TODO: check weather it would be better to set it to N+1
-                self.nodes[i].set_miss(0);
+                // Missing them means leaving the tree, which is indicated by the sentinel.
+                self.nodes[i].set_miss(Node::MISS_SENTINEL);
             } else {
                 let miss = self.nodes[i].miss();
                 let miss = self.nodes[miss].right();
                 self.nodes[i].set_miss(miss);
             }
         }
-        self.nodes[0].set_miss(0);
+        self.nodes[0].set_miss(Node::MISS_SENTINEL);
+    }
+    ///
+    /// Converts nodes stored before `MISS_SENTINEL` was introduced, when a miss pointer of 0
+    /// indicated that the ray left the tree.
+    /// The root is never the target of a miss pointer, so every miss pointer of 0 is replaced by
+    /// the sentinel. Converting nodes that already use the sentinel does not change them.
+    ///
+    pub fn migrate_legacy_misses(nodes: &mut [Node]) {
+        for node in nodes.iter_mut() {
+            if node.miss() == 0 {
+                node.set_miss(Node::MISS_SENTINEL);
+            }
+        }
     }
     ///
     /// Returns AABB of this BVH. This can be used to generate a TLAS.
@@ -360,6 +936,375 @@ impl<Node: BVHNode> BVH<Node> {
     pub fn nodes(&self) -> &[Node]{
         &self.nodes
     }
+
+    pub fn indices(&self) -> &[Node::ExternIndex] {
+        &self.indices
+    }
+
+    ///
+    /// Recomputes the AABBs of all nodes after the primitives moved, keeping the topology of
+    /// the tree. This is a lot cheaper than a rebuild but the quality of the tree degrades the
+    /// further the primitives move from where they were when it was built.
+    ///
+    /// Since the children of a node always come after it in the pre-order layout, a single
+    /// reverse pass over the nodes updates them bottom up.
+    /// References clipped by spatial splits are refitted to the whole primitive.
+    ///
+    /// Fails with `NonFiniteBounds` if the AABB of the primitive at position `index` in
+    /// `indices` contains NaNs or infinities, in which case the tree is left unchanged.
+    ///
+    pub fn refit(
+        &mut self,
+        leaf_aabb: impl Fn(Node::ExternIndex) -> AABB,
+    ) -> Result<(), BuildError> {
+        let aabbs: Vec<AABB> = self.indices.iter().map(|&index| leaf_aabb(index)).collect();
+        for (index, aabb) in aabbs.iter().enumerate() {
+            if aabb.min.iter().chain(aabb.max.iter()).any(|x| !x.is_finite()) {
+                return Err(BuildError::NonFiniteBounds { index });
+            }
+        }
+        for i in (0..self.nodes.len()).rev() {
+            let aabb = if self.nodes[i].is_leaf() {
+                aabbs[self.nodes[i].range()]
+                    .iter()
+                    .copied()
+                    .fold(AABB::empty(), AABB::grow)
+            } else {
+                let right = self.nodes[i].right();
+                self.nodes[i + 1].aabb().grow(self.nodes[right].aabb())
+            };
+            self.nodes[i].set_aabb(aabb);
+        }
+        self.aabb = self.nodes[0].aabb();
+        Ok(())
+    }
+
+    ///
+    /// Borrows the nodes and indices, which is how the queries below are implemented.
+    ///
+    pub fn view(&self) -> BVHView<'_, Node> {
+        BVHView {
+            nodes: &self.nodes,
+            indices: &self.indices,
+            aabb: self.aabb,
+        }
+    }
+
+    ///
+    /// SAH cost of the whole tree, see `BVHView::sah_cost`.
+    ///
+    pub fn sah_cost(&self, config: &BuildConfig) -> f32 {
+        self.view().sah_cost(config)
+    }
+
+    ///
+    /// Checks the invariants established by the builders, see `BVHView::validate`.
+    ///
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        self.view().validate()
+    }
+
+    ///
+    /// Finds the closest intersection of the ray with the primitives in the interval [tmin, tmax],
+    /// see `BVHView::intersect_closest`.
+    ///
+    pub fn intersect_closest<H, F>(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        prim_test: F,
+    ) -> Option<(Node::ExternIndex, H)>
+    where
+        H: Intersection,
+        F: FnMut(Node::ExternIndex, &Ray, f32, f32) -> Option<H>,
+    {
+        self.view().intersect_closest(ray, tmin, tmax, prim_test)
+    }
+
+    ///
+    /// Tests if the ray hits any primitive in the interval [0, tmax], see
+    /// `BVHView::intersect_any`.
+    ///
+    pub fn intersect_any<F>(&self, ray: &Ray, tmax: f32, prim_test: F) -> bool
+    where
+        F: FnMut(Node::ExternIndex, &Ray, f32) -> bool,
+    {
+        self.view().intersect_any(ray, tmax, prim_test)
+    }
+}
+
+impl<'a, Node: BVHNode> BVHView<'a, Node> {
+    ///
+    /// Borrows the nodes and indices of a BVH, the AABB is taken from the root.
+    /// The nodes are not checked, use `validate` for that.
+    ///
+    pub fn new(nodes: &'a [Node], indices: &'a [Node::ExternIndex]) -> Self {
+        let aabb = nodes.first().map_or(AABB::empty(), |root| root.aabb());
+        Self {
+            nodes,
+            indices,
+            aabb,
+        }
+    }
+
+    pub fn aabb(&self) -> AABB {
+        self.aabb
+    }
+
+    pub fn nodes(&self) -> &'a [Node] {
+        self.nodes
+    }
+
+    pub fn indices(&self) -> &'a [Node::ExternIndex] {
+        self.indices
+    }
+
+    ///
+    /// SAH cost of the whole tree, which can be used to compare the quality of trees:
+    ///
+    /// (C_trav * sum(SA_N) + C_isect * sum(SA_L * N_L)) / SA_root
+    ///
+    /// where N are the inner nodes and L the leaves with N_L primitives.
+    ///
+    pub fn sah_cost(&self, config: &BuildConfig) -> f32 {
+        let root_sa = self.aabb.surface_area();
+        let cost: f32 = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let sa = node.aabb().surface_area();
+                if node.is_leaf() {
+                    config.intersection_cost * node.range().len() as f32 * sa
+                } else {
+                    config.traversal_cost * sa
+                }
+            })
+            .sum();
+        if root_sa > 0. {
+            cost / root_sa
+        } else {
+            // All nodes are hit by the same rays.
+            self.nodes
+                .iter()
+                .map(|node| {
+                    if node.is_leaf() {
+                        config.leaf_cost(node.range().len())
+                    } else {
+                        config.traversal_cost
+                    }
+                })
+                .sum()
+        }
+    }
+
+    ///
+    /// Checks the invariants established by the builders:
+    /// * The left child of a node is at i + 1 and its right child follows the left subtree
+    ///   (pre-order layout).
+    /// * The miss pointer of every node is the right child of its pivot, or `MISS_SENTINEL` for
+    ///   the right most nodes (see `sweep_pivot`).
+    /// * The stackless walk visits every leaf exactly once when every AABB is hit.
+    /// * The leaves reference consecutive, non empty ranges covering all indices.
+    /// * The AABBs of children are contained in the AABBs of their parents.
+    /// * `aabb()` matches the AABB of the root node.
+    ///
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.nodes.is_empty() {
+            return Err(ValidationError::Empty);
+        }
+        let root = self.nodes[0].aabb();
+        if root.min != self.aabb.min || root.max != self.aabb.max {
+            return Err(ValidationError::RootAabbMismatch);
+        }
+
+        let next_index = self.validate_structure()?;
+        if next_index != self.indices.len() {
+            return Err(ValidationError::UnreferencedIndices { start: next_index });
+        }
+
+        // Walk the tree as if every AABB would be hit.
+        let mut visits = vec![0; self.nodes.len()];
+        let mut i = 0;
+        while i != Node::MISS_SENTINEL {
+            visits[i] += 1;
+            if visits[i] > 1 {
+                break;
+            }
+            if self.nodes[i].is_leaf() {
+                i = self.nodes[i].miss();
+            } else {
+                i += 1;
+            }
+        }
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.is_leaf() && visits[index] != 1 {
+                return Err(ValidationError::LeafVisits {
+                    index,
+                    visits: visits[index],
+                });
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Checks the layout, miss pointers, leaf ranges and bounds of the tree in pre-order and
+    /// returns the end of the primitive range of the last leaf.
+    ///
+    /// The nodes are visited with an explicit stack, since the files loaded through `BVHView`
+    /// are untrusted and a degenerate tree can be as deep as it has nodes. Every visited node
+    /// has to directly follow the subtree before it, so every node is visited at most once.
+    ///
+    fn validate_structure(&self) -> Result<usize, ValidationError> {
+        // The subtrees still to visit as (index, expected miss pointer, parent). The root has no
+        // parent, which is only used to report a right child not following its left subtree.
+        let mut stack = vec![(0, Node::MISS_SENTINEL, 0)];
+        // Index after the last visited node.
+        let mut end = 0;
+        // Start of the primitive range expected for the next leaf.
+        let mut next_index = 0;
+        while let Some((index, miss, parent)) = stack.pop() {
+            if index != end {
+                return Err(ValidationError::InvalidRight {
+                    index: parent,
+                    right: index,
+                });
+            }
+            let node = &self.nodes[index];
+            if node.miss() != miss {
+                return Err(ValidationError::InvalidMiss {
+                    index,
+                    miss: node.miss(),
+                    expected: miss,
+                });
+            }
+            end = index + 1;
+            if node.is_leaf() {
+                let range = node.range();
+                if range.start != next_index || range.is_empty() || range.end > self.indices.len()
+                {
+                    return Err(ValidationError::InvalidLeafRange { index });
+                }
+                next_index = range.end;
+                continue;
+            }
+
+            let left = index + 1;
+            let right = node.right();
+            if left >= self.nodes.len() {
+                return Err(ValidationError::LeftOutOfRange { index });
+            }
+            if right <= left || right >= self.nodes.len() {
+                return Err(ValidationError::InvalidRight { index, right });
+            }
+            for child in [left, right] {
+                if !node.aabb().contains(&self.nodes[child].aabb()) {
+                    return Err(ValidationError::ChildNotContained {
+                        parent: index,
+                        child,
+                    });
+                }
+            }
+
+            // The pivot of the left child is this node and the right child inherits our pivot.
+            stack.push((right, miss, index));
+            stack.push((left, right, index));
+        }
+        if end != self.nodes.len() {
+            return Err(ValidationError::TrailingNodes { end });
+        }
+        Ok(next_index)
+    }
+
+    ///
+    /// Finds the closest intersection of the ray with the primitives in the interval [tmin, tmax].
+    ///
+    /// The tree is walked without a stack by following the left child (i + 1) on a hit and the
+    /// miss pointer otherwise, exactly as the shader would do.
+    /// `prim_test` is called with the extern index of every primitive in a leaf whose AABB is
+    /// hit, the ray and the current interval and should return the intersection with that
+    /// primitive if any.
+    ///
+    /// Returns the extern index of the closest primitive together with its intersection.
+    ///
+    pub fn intersect_closest<H, F>(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        mut prim_test: F,
+    ) -> Option<(Node::ExternIndex, H)>
+    where
+        H: Intersection,
+        F: FnMut(Node::ExternIndex, &Ray, f32, f32) -> Option<H>,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut closest = None;
+        let mut tmax = tmax;
+        let mut i = 0;
+        // The sentinel miss pointer indicates that the ray left the tree.
+        while i != Node::MISS_SENTINEL {
+            let node = &self.nodes[i];
+            if node.aabb().intersect_ray(ray, tmin, tmax).is_some() {
+                if node.is_leaf() {
+                    for &index in &self.indices[node.range()] {
+                        if let Some(hit) = prim_test(index, ray, tmin, tmax) {
+                            // Shrinking the interval culls every node behind the closest hit.
+                            if hit.t() >= tmin && hit.t() <= tmax {
+                                tmax = hit.t();
+                                closest = Some((index, hit));
+                            }
+                        }
+                    }
+                    i = node.miss();
+                } else {
+                    i += 1;
+                }
+            } else {
+                i = node.miss();
+            }
+        }
+        closest
+    }
+
+    ///
+    /// Tests if the ray hits any primitive in the interval [0, tmax].
+    ///
+    /// Walks the tree in the same way as `intersect_closest` but terminates at the first leaf
+    /// for which `prim_test` confirms a hit. This is intended for occlusion queries such as
+    /// shadow rays, where the closest hit is not of interest.
+    /// `prim_test` is called with the extern index of the primitive, the ray and tmax.
+    ///
+    pub fn intersect_any<F>(&self, ray: &Ray, tmax: f32, mut prim_test: F) -> bool
+    where
+        F: FnMut(Node::ExternIndex, &Ray, f32) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let mut i = 0;
+        while i != Node::MISS_SENTINEL {
+            let node = &self.nodes[i];
+            if node.aabb().intersect_ray(ray, 0., tmax).is_some() {
+                if node.is_leaf() {
+                    for &index in &self.indices[node.range()] {
+                        if prim_test(index, ray, tmax) {
+                            return true;
+                        }
+                    }
+                    i = node.miss();
+                } else {
+                    i += 1;
+                }
+            } else {
+                i = node.miss();
+            }
+        }
+        false
+    }
 }
 impl<Node: BVHNode + std::fmt::Debug> BVH<Node> {
     pub fn print_rec(&self, index: usize, indent_string: &mut String) {
@@ -375,8 +1320,7 @@ impl<Node: BVHNode + std::fmt::Debug> BVH<Node> {
     }
 }
 #[cfg(test)]
-mod test {
-    use crate::aabb::*;
+pub(crate) mod test {
     use crate::bvh::*;
     use crate::glsl_bvh::*;
 
@@ -468,6 +1412,495 @@ mod test {
         mesh
     }
 
+    pub const SWEEP: BuildConfig = BuildConfig {
+        strategy: BuildStrategy::FullSweep,
+        max_leaf_size: 4,
+        traversal_cost: 1.,
+        intersection_cost: 1.,
+    };
+    pub const BINNED_8: BuildConfig = SWEEP.with(BuildStrategy::Binned { bins: 8 });
+    pub const BINNED_16: BuildConfig = SWEEP.with(BuildStrategy::Binned { bins: 16 });
+
+    /// Small linear congruential generator to get reproducible scenes without extra dependencies.
+    pub struct Lcg(pub u64);
+    impl Lcg {
+        pub fn next_f32(&mut self) -> f32 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 40) as f32 / (1u64 << 24) as f32
+        }
+        pub fn next_vec3(&mut self, scale: f32) -> [f32; 3] {
+            [
+                self.next_f32() * scale,
+                self.next_f32() * scale,
+                self.next_f32() * scale,
+            ]
+        }
+    }
+
+    pub fn random_aabbs(n: usize, seed: u64) -> Vec<AABB> {
+        let mut rng = Lcg(seed);
+        (0..n)
+            .map(|_| {
+                let min = rng.next_vec3(10.);
+                let size = rng.next_vec3(1.);
+                AABB {
+                    min,
+                    max: [min[0] + size[0], min[1] + size[1], min[2] + size[2]],
+                }
+            })
+            .collect()
+    }
+
+    pub fn random_rays(n: usize, seed: u64) -> Vec<Ray> {
+        let mut rng = Lcg(seed);
+        (0..n)
+            .map(|_| {
+                let origin = rng.next_vec3(10.);
+                let target = rng.next_vec3(10.);
+                Ray::new(
+                    origin,
+                    [
+                        target[0] - origin[0],
+                        target[1] - origin[1],
+                        target[2] - origin[2],
+                    ],
+                )
+            })
+            .collect()
+    }
+
+    fn brute_force_closest(aabbs: &[AABB], ray: &Ray) -> Option<(usize, f32)> {
+        aabbs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, aabb)| aabb.intersect_ray(ray, 0., f32::INFINITY).map(|t| (i, t.0)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+    }
+
+    #[test]
+    pub fn test_intersect_closest() {
+        let aabbs = random_aabbs(500, 1);
+        let sweep = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        let buckets = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+
+        for ray in random_rays(500, 2) {
+            let expected = brute_force_closest(&aabbs, &ray).map(|hit| hit.1);
+            for bvh in [&sweep, &buckets] {
+                let hit = bvh
+                    .intersect_closest(&ray, 0., f32::INFINITY, |i, ray, tmin, tmax| {
+                        aabbs[i].intersect_ray(ray, tmin, tmax).map(|t| t.0)
+                    })
+                    .map(|hit| hit.1);
+                assert_eq!(hit, expected);
+            }
+        }
+    }
+
+    ///
+    /// Asserts that the bounds of every node in the subtree contain the AABBs of its leaves
+    /// and returns the union of these.
+    ///
+    fn assert_bounds(bvh: &GlslBVH, aabbs: &[AABB], index: usize) -> AABB {
+        let node = &bvh.nodes[index];
+        let aabb = if node.is_leaf() {
+            bvh.indices[node.range()]
+                .iter()
+                .fold(AABB::empty(), |aabb, &i| aabb.grow(aabbs[i]))
+        } else {
+            let left = assert_bounds(bvh, aabbs, index + 1);
+            let right = assert_bounds(bvh, aabbs, node.right());
+            left.grow(right)
+        };
+        for axis in 0..3 {
+            assert!(
+                node.min[axis] <= aabb.min[axis] && node.max[axis] >= aabb.max[axis],
+                "node {} {:?} does not contain {:?}",
+                index,
+                node,
+                aabb
+            );
+        }
+        aabb
+    }
+
+    #[test]
+    pub fn test_sweep_bounds() {
+        // Regression test: the left aabb of the split was only grown when the SAH improved.
+        let aabbs = random_aabbs(300, 3);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        assert_bounds(&bvh, &aabbs, 0);
+    }
+
+    #[test]
+    pub fn test_buckets_bounds() {
+        // Regression test: the accumulated aabbs of the right side were read reversed.
+        let aabbs = random_aabbs(300, 4);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_8).unwrap();
+        assert_bounds(&bvh, &aabbs, 0);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+        assert_bounds(&bvh, &aabbs, 0);
+
+        // Only the first and the last bucket are occupied, which falls back to splitting in the
+        // middle of the children.
+        let small = AABB {
+            min: [0.; 3],
+            max: [0.5; 3],
+        };
+        let large = AABB {
+            min: [9.; 3],
+            max: [10.; 3],
+        };
+        let aabbs = [small, small, small, large, large, large, large];
+        let config = BuildConfig {
+            max_leaf_size: 1,
+            ..BINNED_8
+        };
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &config).unwrap();
+        assert_bounds(&bvh, &aabbs, 0);
+    }
+
+    #[test]
+    pub fn test_intersect_any() {
+        let aabbs = random_aabbs(500, 3);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+
+        for ray in random_rays(500, 4) {
+            for tmax in [0.01, 0.1, 1.] {
+                let expected = aabbs
+                    .iter()
+                    .any(|aabb| aabb.intersect_ray(&ray, 0., tmax).is_some());
+                let mut found = false;
+                let hit = bvh.intersect_any(&ray, tmax, |i, ray, tmax| {
+                    // No more primitives should be tested after the first hit.
+                    assert!(!found);
+                    found = aabbs[i].intersect_ray(ray, 0., tmax).is_some();
+                    found
+                });
+                assert_eq!(hit, expected);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_build_errors() {
+        let empty: [(usize, AABB); 0] = [];
+        assert_eq!(
+            GlslBVH::build(empty.iter().copied(), &SWEEP).unwrap_err(),
+            BuildError::EmptyInput
+        );
+        assert_eq!(
+            GlslBVH::build(empty.iter().copied(), &BINNED_16).unwrap_err(),
+            BuildError::EmptyInput
+        );
+
+        let mut aabbs = random_aabbs(10, 5);
+        aabbs[3].max[1] = f32::NAN;
+        assert_eq!(
+            GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap_err(),
+            BuildError::NonFiniteBounds { index: 3 }
+        );
+        aabbs[3].max[1] = f32::INFINITY;
+        assert_eq!(
+            GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_8).unwrap_err(),
+            BuildError::NonFiniteBounds { index: 3 }
+        );
+    }
+
+    #[test]
+    pub fn test_refit() {
+        let mut aabbs = random_aabbs(1000, 21);
+        let mut bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_8).unwrap();
+        let nodes = bvh.nodes().len();
+
+        // Move every box by a different amount.
+        let mut rng = Lcg(22);
+        for aabb in aabbs.iter_mut() {
+            let delta = rng.next_vec3(2.);
+            for ((min, max), d) in aabb.min.iter_mut().zip(aabb.max.iter_mut()).zip(delta) {
+                *min += d;
+                *max += d;
+            }
+        }
+        bvh.refit(|i| aabbs[i]).unwrap();
+        assert_eq!(bvh.nodes().len(), nodes);
+        assert_eq!(bvh.validate(), Ok(()));
+        let aabb = aabbs.iter().copied().fold(AABB::empty(), AABB::grow);
+        assert_eq!(bvh.aabb().min, aabb.min);
+        assert_eq!(bvh.aabb().max, aabb.max);
+
+        for ray in random_rays(200, 23) {
+            let expected = brute_force_closest(&aabbs, &ray).map(|hit| hit.1);
+            let hit = bvh
+                .intersect_closest(&ray, 0., f32::INFINITY, |i, ray, tmin, tmax| {
+                    aabbs[i].intersect_ray(ray, tmin, tmax).map(|t| t.0)
+                })
+                .map(|hit| hit.1);
+            assert_eq!(hit, expected);
+        }
+
+        // Non-finite bounds are rejected and leave the tree as it was.
+        let nodes: Vec<u8> = bytemuck::cast_slice(bvh.nodes()).to_vec();
+        aabbs[7].min[0] = f32::NAN;
+        let position = bvh.indices().iter().position(|&i| i == 7).unwrap();
+        assert_eq!(
+            bvh.refit(|i| aabbs[i]),
+            Err(BuildError::NonFiniteBounds { index: position })
+        );
+        assert_eq!(bytemuck::cast_slice::<GlslBVHNode, u8>(bvh.nodes()), nodes);
+    }
+
+    #[test]
+    pub fn test_build_degenerate() {
+        // All centroids coincide and the parent has no volume.
+        let aabbs = vec![AABB::from([1., 2., 3.]); 17];
+        let config = BuildConfig {
+            max_leaf_size: 1,
+            ..Default::default()
+        };
+        for bvh in [
+            GlslBVH::build(
+                aabbs.iter().copied().enumerate(),
+                &config.with(BuildStrategy::FullSweep),
+            )
+            .unwrap(),
+            GlslBVH::build(
+                aabbs.iter().copied().enumerate(),
+                &config.with(BuildStrategy::Binned { bins: 16 }),
+            )
+            .unwrap(),
+        ] {
+            assert_eq!(bvh.nodes().len(), 2 * aabbs.len() - 1);
+            for node in bvh.nodes() {
+                assert_eq!(node.aabb().min, [1., 2., 3.]);
+                assert_eq!(node.aabb().max, [1., 2., 3.]);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_max_leaf_size() {
+        let aabbs = random_aabbs(300, 7);
+        for max_leaf_size in [1, 2, 8] {
+            let config = BuildConfig {
+                max_leaf_size,
+                // Make leaves as large as allowed.
+                traversal_cost: 1000.,
+                ..Default::default()
+            };
+            for bvh in [
+                GlslBVH::build(
+                    aabbs.iter().copied().enumerate(),
+                    &config.with(BuildStrategy::FullSweep),
+                )
+                .unwrap(),
+                GlslBVH::build(
+                    aabbs.iter().copied().enumerate(),
+                    &config.with(BuildStrategy::Binned { bins: 8 }),
+                )
+                .unwrap(),
+            ] {
+                assert_eq!(bvh.validate(), Ok(()));
+                let leaves = bvh.nodes().iter().filter(|n| n.is_leaf());
+                assert!(leaves.clone().all(|n| n.range().len() <= max_leaf_size));
+                if max_leaf_size > 1 {
+                    assert!(leaves.clone().any(|n| n.range().len() > 1));
+                }
+                let mut indices = bvh.indices().to_vec();
+                indices.sort();
+                assert_eq!(indices, (0..aabbs.len()).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_build_strategy() {
+        assert_eq!("sweep".parse(), Ok(BuildStrategy::FullSweep));
+        assert_eq!("binned".parse(), Ok(BuildStrategy::Binned { bins: 16 }));
+        assert_eq!("binned:5".parse(), Ok(BuildStrategy::Binned { bins: 5 }));
+        assert_eq!("morton".parse(), Ok(BuildStrategy::Morton));
+        assert_eq!("spatial:4".parse(), Ok(BuildStrategy::Spatial { bins: 4 }));
+        assert!("binned:x".parse::<BuildStrategy>().is_err());
+        assert!("octree".parse::<BuildStrategy>().is_err());
+
+        let aabbs = random_aabbs(200, 9);
+        for bins in [2, 3, 32] {
+            let bvh = GlslBVH::build(
+                aabbs.iter().copied().enumerate(),
+                &SWEEP.with(BuildStrategy::Binned { bins }),
+            )
+            .unwrap();
+            assert_eq!(bvh.validate(), Ok(()));
+        }
+        assert_eq!(
+            GlslBVH::build(
+                aabbs.iter().copied().enumerate(),
+                &SWEEP.with(BuildStrategy::Binned { bins: 1 }),
+            )
+            .unwrap_err(),
+            BuildError::InvalidBinCount { bins: 1 }
+        );
+    }
+
+    #[test]
+    pub fn test_sah_cost() {
+        let config = BuildConfig {
+            strategy: BuildStrategy::FullSweep,
+            max_leaf_size: 1,
+            traversal_cost: 1.,
+            intersection_cost: 2.,
+        };
+        let unit = AABB {
+            min: [0., 0., 0.],
+            max: [1., 1., 1.],
+        };
+        let single = GlslBVH::build([(0, unit)].into_iter(), &config).unwrap();
+        assert_eq!(single.sah_cost(&config), 2.);
+
+        // Two unit cubes next to each other: the root with surface area 10 and two leaves with
+        // surface area 6 each.
+        let right = AABB {
+            min: [1., 0., 0.],
+            max: [2., 1., 1.],
+        };
+        let bvh = GlslBVH::build([(0, unit), (1, right)].into_iter(), &config).unwrap();
+        assert_eq!(bvh.sah_cost(&config), 1. + 2. * 2. * 6. / 10.);
+    }
+
+    #[test]
+    pub fn test_sah_counts() {
+        // Thin slabs along the x axis. The sum of the surface areas of the children is the same
+        // for every split, so comparing only the surface areas would peel off one slab at a time.
+        // Splitting off the first k slabs costs
+        // (40 (k - 0.9) + 200) k + (40 (63.1 - k) + 200) (64 - k),
+        // which is symmetric around and minimal at k = 32.
+        let aabbs = (0..64)
+            .map(|i| AABB {
+                min: [i as f32, 0., 0.],
+                max: [i as f32 + 0.1, 10., 10.],
+            })
+            .collect::<Vec<_>>();
+        let config = BuildConfig {
+            max_leaf_size: 1,
+            ..Default::default()
+        };
+        for bvh in [
+            GlslBVH::build(
+                aabbs.iter().copied().enumerate(),
+                &config.with(BuildStrategy::FullSweep),
+            )
+            .unwrap(),
+            GlslBVH::build(
+                aabbs.iter().copied().enumerate(),
+                &config.with(BuildStrategy::Binned { bins: 16 }),
+            )
+            .unwrap(),
+        ] {
+            assert_eq!(bvh.validate(), Ok(()));
+            let right = bvh.nodes()[0].right();
+            let l_n: usize = bvh.nodes()[1..right]
+                .iter()
+                .filter(|n| n.is_leaf())
+                .map(|n| n.range().len())
+                .sum();
+            assert_eq!(l_n, 32);
+            // The leaves of the left subtree come first and hold the slabs with lower x.
+            assert!(bvh.indices()[..32].iter().all(|&i| i < 32));
+        }
+    }
+
+    #[test]
+    pub fn test_sweep_all_axes() {
+        // Two clusters separated along y, made of boxes that are long along x. The largest axis
+        // of the root is x, but only a split along y separates the clusters.
+        let mut rng = Lcg(10);
+        let aabbs = (0..64)
+            .map(|i| {
+                let y = if i % 2 == 0 { 0. } else { 5. } + rng.next_f32();
+                let z = rng.next_f32();
+                AABB {
+                    min: [0., y, z],
+                    max: [10., y + 0.1, z + 0.1],
+                }
+            })
+            .collect::<Vec<_>>();
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        assert_eq!(bvh.validate(), Ok(()));
+        let left = bvh.nodes()[1].aabb();
+        let right = bvh.nodes()[bvh.nodes()[0].right()].aabb();
+        assert!(left.max[1] < 2. || left.min[1] > 4.);
+        assert!(right.max[1] < 2. || right.min[1] > 4.);
+
+        // The full sweep should not be worse than the binned builder.
+        let aabbs = random_aabbs(2000, 11);
+        let sweep = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        let binned = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_8).unwrap();
+        assert!(sweep.sah_cost(&SWEEP) <= binned.sah_cost(&SWEEP));
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let aabbs = random_aabbs(100, 6);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_8).unwrap();
+        assert_eq!(bvh.validate(), Ok(()));
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        assert_eq!(bvh.validate(), Ok(()));
+
+        let single = GlslBVH::build(aabbs.iter().copied().enumerate().take(1), &SWEEP).unwrap();
+        assert_eq!(single.validate(), Ok(()));
+
+        let mut corrupted = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        let leaf = corrupted.nodes.iter().position(|n| n.is_leaf()).unwrap();
+        corrupted.nodes[leaf].miss = 0;
+        assert!(matches!(
+            corrupted.validate(),
+            Err(ValidationError::InvalidMiss { index, .. }) if index == leaf
+        ));
+
+        let mut corrupted = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        corrupted.nodes[0].right += 1;
+        assert!(corrupted.validate().is_err());
+
+        let mut corrupted = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        corrupted.nodes[1].max[0] += 100.;
+        assert_eq!(
+            corrupted.validate(),
+            Err(ValidationError::ChildNotContained {
+                parent: 0,
+                child: 1
+            })
+        );
+
+        let mut corrupted = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        corrupted.nodes[0].min[2] -= 1.;
+        assert_eq!(corrupted.validate(), Err(ValidationError::RootAabbMismatch));
+    }
+
+    #[test]
+    pub fn test_migrate_legacy_misses() {
+        let aabbs = random_aabbs(100, 30);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_8).unwrap();
+        assert_eq!(bvh.nodes[0].miss, u32::MAX);
+        let last = bvh.nodes.len() - 1;
+        assert_eq!(bvh.nodes[last].miss(), GlslBVHNode::MISS_SENTINEL);
+
+        let mut legacy = bvh.nodes.clone();
+        for node in legacy.iter_mut() {
+            if node.miss() == GlslBVHNode::MISS_SENTINEL {
+                node.set_miss(0);
+            }
+        }
+        for _ in 0..2 {
+            GlslBVH::migrate_legacy_misses(&mut legacy);
+            assert_eq!(
+                bytemuck::cast_slice::<GlslBVHNode, u8>(&legacy),
+                bytemuck::cast_slice::<GlslBVHNode, u8>(&bvh.nodes)
+            );
+        }
+    }
+
     #[test]
     pub fn test_suzanne_8() {
         let mesh = load_suzanne();
@@ -475,12 +1908,14 @@ mod test {
         {
             time_test::time_test!("build_buckets_8");
             for _ in 0..1 {
-                GlslBVH::build_buckets_8(
+                GlslBVH::build(
                     mesh.tris
                         .iter()
                         .enumerate()
                         .map(|(i, tri)| (i, mesh.get_for_tri(tri).into())),
-                );
+                    &BINNED_8,
+                )
+                .unwrap();
             }
         }
     }
@@ -492,12 +1927,14 @@ mod test {
         {
             time_test::time_test!("build_buckets_16");
             for _ in 0..1 {
-                GlslBVH::build_buckets_16(
+                GlslBVH::build(
                     mesh.tris
                         .iter()
                         .enumerate()
                         .map(|(i, tri)| (i, mesh.get_for_tri(tri).into())),
-                );
+                    &BINNED_16,
+                )
+                .unwrap();
             }
         }
     }
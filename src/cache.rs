@@ -0,0 +1,657 @@
+use crate::aabb::*;
+use crate::bvh::*;
+use std::io::{Read, Write};
+
+///
+/// Magic bytes at the start of every cache file.
+///
+pub const CACHE_MAGIC: [u8; 8] = *b"BVH01BIN";
+///
+/// Version of the cache format, increased on every incompatible change.
+/// 1: Initial version, miss pointers use `BVHNode::MISS_SENTINEL`.
+/// 2: The node and index types are identified by `CacheType::CACHE_TYPE_ID`.
+///
+pub const CACHE_VERSION: u32 = 2;
+///
+/// The header, the nodes and the indices start at multiples of this, so that the sections of a
+/// file loaded to or mapped at an aligned address can be used in place.
+///
+pub const CACHE_ALIGNMENT: usize = 16;
+
+///
+/// Header of a cache file, written in the byte order of the machine.
+/// Files written with the other byte order are rejected because of their version.
+///
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct CacheHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub node_stride: u32,
+    /// `CacheType::CACHE_TYPE_ID` of the nodes.
+    pub node_type: u64,
+    /// `CacheType::CACHE_TYPE_ID` of the extern indices.
+    pub index_type: u64,
+    pub index_stride: u32,
+    /// `BuildStrategy` (see `encode_strategy`).
+    pub strategy: u32,
+    pub bins: u64,
+    pub max_leaf_size: u64,
+    pub traversal_cost: f32,
+    pub intersection_cost: f32,
+    pub node_count: u64,
+    pub index_count: u64,
+    pub aabb_min: [f32; 3],
+    pub aabb_max: [f32; 3],
+    pub content_hash: u64,
+    pub reserved: [u32; 4],
+}
+
+///
+/// Node and index types that can be stored in cache files.
+///
+/// The id is written to the header and has to differ between all node types and between all
+/// index types. Unlike `std::any::type_name` it does not depend on the compiler or the module
+/// path, so it has to be changed by hand whenever the layout or meaning of the type changes.
+///
+pub trait CacheType: bytemuck::Pod {
+    const CACHE_TYPE_ID: u64;
+}
+
+impl CacheType for u16 {
+    const CACHE_TYPE_ID: u64 = 1;
+}
+impl CacheType for u32 {
+    const CACHE_TYPE_ID: u64 = 2;
+}
+impl CacheType for u64 {
+    const CACHE_TYPE_ID: u64 = 3;
+}
+// The stride distinguishes the width on different platforms.
+impl CacheType for usize {
+    const CACHE_TYPE_ID: u64 = 4;
+}
+
+///
+/// Identifies the input of a build, a cache is only loaded if its key matches.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct CacheKey {
+    pub config: BuildConfig,
+    /// Hash of the primitives the BVH was built over (see `content_hash`).
+    pub content_hash: u64,
+}
+
+impl CacheKey {
+    ///
+    /// Key of a build over the primitives stored in `data`, for example the vertices and
+    /// indices of a mesh passed through `bytemuck::cast_slice`.
+    ///
+    pub fn new(config: &BuildConfig, data: &[u8]) -> Self {
+        Self {
+            config: *config,
+            content_hash: content_hash(data),
+        }
+    }
+}
+
+///
+/// 64 bit FNV-1a hash of the data.
+/// Unlike the hashers of the standard library it is the same on every platform and version.
+///
+pub fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(std::io::Error),
+    /// The file does not start with `CACHE_MAGIC`.
+    InvalidMagic,
+    /// The file was written with another version of the format or byte order.
+    UnsupportedVersion {
+        version: u32,
+    },
+    /// The file holds other node or index types.
+    TypeMismatch,
+    /// The BVH was built with other settings or over other primitives.
+    Stale,
+    /// The header describes sections that cannot be loaded or does not match them.
+    InvalidHeader,
+    /// The stored tree is corrupted.
+    InvalidTree(ValidationError),
+    /// The sections cannot be borrowed as nodes and indices, because the bytes do not start at
+    /// an address aligned to `CACHE_ALIGNMENT`.
+    Misaligned(bytemuck::PodCastError),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::Io(err) => write!(f, "{}", err),
+            CacheError::InvalidMagic => write!(f, "not a BVH cache"),
+            CacheError::UnsupportedVersion { version } => {
+                write!(f, "unsupported cache version {}", version)
+            }
+            CacheError::TypeMismatch => write!(f, "the cache holds other node or index types"),
+            CacheError::Stale => write!(f, "the cache was built from other primitives"),
+            CacheError::InvalidHeader => write!(f, "the cache header is corrupted"),
+            CacheError::InvalidTree(err) => write!(f, "the cached tree is corrupted: {}", err),
+            CacheError::Misaligned(err) => write!(f, "cannot borrow the cache: {:?}", err),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<std::io::Error> for CacheError {
+    fn from(err: std::io::Error) -> Self {
+        CacheError::Io(err)
+    }
+}
+
+fn encode_strategy(strategy: BuildStrategy) -> (u32, u64) {
+    match strategy {
+        BuildStrategy::FullSweep => (0, 0),
+        BuildStrategy::Binned { bins } => (1, bins as u64),
+        BuildStrategy::Morton => (2, 0),
+        BuildStrategy::Spatial { bins } => (3, bins as u64),
+    }
+}
+
+///
+/// Number of padding bytes following a section of `len` bytes.
+///
+pub(crate) fn section_padding(len: usize) -> usize {
+    (CACHE_ALIGNMENT - len % CACHE_ALIGNMENT) % CACHE_ALIGNMENT
+}
+
+impl CacheHeader {
+    pub(crate) fn new<Node: BVHNode + CacheType>(
+        key: &CacheKey,
+        aabb: AABB,
+        node_count: usize,
+        index_count: usize,
+    ) -> Self
+    where
+        Node::ExternIndex: CacheType,
+    {
+        let (strategy, bins) = encode_strategy(key.config.strategy);
+        Self {
+            magic: CACHE_MAGIC,
+            version: CACHE_VERSION,
+            node_stride: std::mem::size_of::<Node>() as u32,
+            node_type: Node::CACHE_TYPE_ID,
+            index_type: Node::ExternIndex::CACHE_TYPE_ID,
+            index_stride: std::mem::size_of::<Node::ExternIndex>() as u32,
+            strategy,
+            bins,
+            max_leaf_size: key.config.max_leaf_size as u64,
+            traversal_cost: key.config.traversal_cost,
+            intersection_cost: key.config.intersection_cost,
+            node_count: node_count as u64,
+            index_count: index_count as u64,
+            aabb_min: aabb.min,
+            aabb_max: aabb.max,
+            content_hash: key.content_hash,
+            reserved: [0; 4],
+        }
+    }
+
+    ///
+    /// Checks that the header belongs to a cache of the node type built with the key and
+    /// returns the number of nodes and indices.
+    ///
+    pub(crate) fn check<Node: BVHNode + CacheType>(
+        &self,
+        key: &CacheKey,
+    ) -> Result<(usize, usize), CacheError>
+    where
+        Node::ExternIndex: CacheType,
+    {
+        if self.magic != CACHE_MAGIC {
+            return Err(CacheError::InvalidMagic);
+        }
+        if self.version != CACHE_VERSION {
+            return Err(CacheError::UnsupportedVersion {
+                version: self.version,
+            });
+        }
+        let expected = Self::new::<Node>(key, self.aabb(), 0, 0);
+        if self.types() != expected.types() {
+            return Err(CacheError::TypeMismatch);
+        }
+        if self.build_key() != expected.build_key() {
+            return Err(CacheError::Stale);
+        }
+        let node_count = usize::try_from(self.node_count).map_err(|_| CacheError::InvalidHeader)?;
+        let index_count =
+            usize::try_from(self.index_count).map_err(|_| CacheError::InvalidHeader)?;
+        Ok((node_count, index_count))
+    }
+
+    fn types(&self) -> (u32, u64, u32, u64) {
+        (
+            self.node_stride,
+            self.node_type,
+            self.index_stride,
+            self.index_type,
+        )
+    }
+
+    ///
+    /// The settings and the content hash, with the costs compared by their bits since they are
+    /// stored and not computed.
+    ///
+    fn build_key(&self) -> (u32, u64, u64, u32, u32, u64) {
+        (
+            self.strategy,
+            self.bins,
+            self.max_leaf_size,
+            self.traversal_cost.to_bits(),
+            self.intersection_cost.to_bits(),
+            self.content_hash,
+        )
+    }
+
+    pub(crate) fn aabb(&self) -> AABB {
+        AABB {
+            min: self.aabb_min,
+            max: self.aabb_max,
+        }
+    }
+}
+
+///
+/// Reads `count` elements into a vector, failing instead of aborting if a corrupted header
+/// asks for more memory than available.
+///
+fn read_section<T: bytemuck::Pod>(
+    reader: &mut impl Read,
+    count: usize,
+) -> Result<Vec<T>, CacheError> {
+    let mut data = Vec::new();
+    data.try_reserve_exact(count)
+        .map_err(|_| CacheError::InvalidHeader)?;
+    data.resize(count, T::zeroed());
+    reader.read_exact(bytemuck::cast_slice_mut(&mut data))?;
+    let mut padding = [0; CACHE_ALIGNMENT];
+    reader.read_exact(&mut padding[..section_padding(count * std::mem::size_of::<T>())])?;
+    Ok(data)
+}
+
+fn write_section<T: bytemuck::Pod>(writer: &mut impl Write, data: &[T]) -> std::io::Result<()> {
+    let bytes: &[u8] = bytemuck::cast_slice(data);
+    writer.write_all(bytes)?;
+    writer.write_all(&[0; CACHE_ALIGNMENT][..section_padding(bytes.len())])
+}
+
+impl<Node: BVHNode + CacheType> BVH<Node>
+where
+    Node::ExternIndex: CacheType,
+{
+    ///
+    /// Writes the BVH to a cache file.
+    /// The file consists of a header holding the key, followed by the nodes and the indices as
+    /// they are in memory, so the nodes can be uploaded straight from the file.
+    ///
+    pub fn write_to(&self, mut writer: impl Write, key: &CacheKey) -> std::io::Result<()> {
+        let header =
+            CacheHeader::new::<Node>(key, self.aabb(), self.nodes.len(), self.indices.len());
+        writer.write_all(bytemuck::bytes_of(&header))?;
+        write_section(&mut writer, &self.nodes)?;
+        write_section(&mut writer, &self.indices)
+    }
+
+    ///
+    /// Reads a BVH written by `write_to`.
+    /// Fails with `CacheError::Stale` if it was built with another key, in which case the BVH
+    /// should be rebuilt and the cache overwritten.
+    ///
+    pub fn read_from(mut reader: impl Read, key: &CacheKey) -> Result<Self, CacheError> {
+        let mut header: CacheHeader = bytemuck::Zeroable::zeroed();
+        reader.read_exact(bytemuck::bytes_of_mut(&mut header))?;
+        let (node_count, index_count) = header.check::<Node>(key)?;
+        let nodes = read_section(&mut reader, node_count)?;
+        let indices = read_section(&mut reader, index_count)?;
+        let bvh = Self::from_raw_parts(nodes, indices);
+        let aabb = header.aabb();
+        if bvh.aabb().min != aabb.min || bvh.aabb().max != aabb.max {
+            return Err(CacheError::InvalidHeader);
+        }
+        bvh.validate().map_err(CacheError::InvalidTree)?;
+        Ok(bvh)
+    }
+}
+
+impl<'a, Node: BVHNode + CacheType> BVHView<'a, Node>
+where
+    Node::ExternIndex: CacheType,
+{
+    ///
+    /// Borrows the nodes and indices of a cache file written by `BVH::write_to` without
+    /// copying them, usually from a memory mapped file.
+    /// Checks the header and validates the tree like `BVH::read_from`.
+    ///
+    pub fn from_bytes(bytes: &'a [u8], key: &CacheKey) -> Result<Self, CacheError> {
+        let truncated = || CacheError::Io(std::io::ErrorKind::UnexpectedEof.into());
+        let header_len = std::mem::size_of::<CacheHeader>();
+        let header: CacheHeader =
+            bytemuck::pod_read_unaligned(bytes.get(..header_len).ok_or_else(truncated)?);
+        let (node_count, index_count) = header.check::<Node>(key)?;
+
+        let node_len = node_count
+            .checked_mul(std::mem::size_of::<Node>())
+            .ok_or(CacheError::InvalidHeader)?;
+        let index_len = index_count
+            .checked_mul(std::mem::size_of::<Node::ExternIndex>())
+            .ok_or(CacheError::InvalidHeader)?;
+        let nodes_start = header_len;
+        let nodes_end = nodes_start
+            .checked_add(node_len)
+            .ok_or(CacheError::InvalidHeader)?;
+        let indices_start = nodes_end
+            .checked_add(section_padding(node_len))
+            .ok_or(CacheError::InvalidHeader)?;
+        let indices_end = indices_start
+            .checked_add(index_len)
+            .ok_or(CacheError::InvalidHeader)?;
+        let nodes = bytes.get(nodes_start..nodes_end).ok_or_else(truncated)?;
+        let indices = bytes.get(indices_start..indices_end).ok_or_else(truncated)?;
+
+        let view = Self::new(
+            bytemuck::try_cast_slice(nodes).map_err(CacheError::Misaligned)?,
+            bytemuck::try_cast_slice(indices).map_err(CacheError::Misaligned)?,
+        );
+        let aabb = header.aabb();
+        if view.aabb().min != aabb.min || view.aabb().max != aabb.max {
+            return Err(CacheError::InvalidHeader);
+        }
+        view.validate().map_err(CacheError::InvalidTree)?;
+        Ok(view)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::cache::*;
+    use crate::compact_bvh::*;
+    use crate::glsl_bvh::*;
+
+    fn aabbs_key(aabbs: &[AABB], config: &BuildConfig) -> CacheKey {
+        let bounds: Vec<[f32; 3]> = aabbs.iter().flat_map(|aabb| [aabb.min, aabb.max]).collect();
+        CacheKey::new(config, bytemuck::cast_slice(&bounds))
+    }
+
+    #[test]
+    fn test_cache() {
+        assert_eq!(std::mem::size_of::<CacheHeader>() % CACHE_ALIGNMENT, 0);
+
+        let aabbs = random_aabbs(1000, 34);
+        let key = aabbs_key(&aabbs, &BINNED_16);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+        let mut file = Vec::new();
+        bvh.write_to(&mut file, &key).unwrap();
+        assert_eq!(file.len() % CACHE_ALIGNMENT, 0);
+
+        let cached = GlslBVH::read_from(file.as_slice(), &key).unwrap();
+        assert_eq!(
+            bytemuck::cast_slice::<GlslBVHNode, u8>(cached.nodes()),
+            bytemuck::cast_slice::<GlslBVHNode, u8>(bvh.nodes())
+        );
+        assert_eq!(cached.indices(), bvh.indices());
+        assert_eq!(cached.aabb().min, bvh.aabb().min);
+        assert_eq!(cached.aabb().max, bvh.aabb().max);
+
+        // The nodes follow the header, ready to be uploaded.
+        let header = std::mem::size_of::<CacheHeader>();
+        let nodes = &file[header..header + std::mem::size_of_val(bvh.nodes())];
+        assert_eq!(nodes, bytemuck::cast_slice::<GlslBVHNode, u8>(bvh.nodes()));
+
+        let compact = CompactBVH::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+        let mut compact_file = Vec::new();
+        compact.write_to(&mut compact_file, &key).unwrap();
+        let cached = CompactBVH::read_from(compact_file.as_slice(), &key).unwrap();
+        assert_eq!(
+            bytemuck::cast_slice::<CompactBVHNode, u8>(cached.nodes()),
+            bytemuck::cast_slice::<CompactBVHNode, u8>(compact.nodes())
+        );
+    }
+
+    ///
+    /// Copies the bytes to a buffer aligned to `CACHE_ALIGNMENT` like a memory mapped file,
+    /// starting at `offset`.
+    ///
+    fn aligned(bytes: &[u8], offset: usize) -> Vec<u128> {
+        let mut storage = vec![0u128; (offset + bytes.len()).div_ceil(16)];
+        bytemuck::cast_slice_mut::<u128, u8>(&mut storage)[offset..offset + bytes.len()]
+            .copy_from_slice(bytes);
+        storage
+    }
+
+    #[test]
+    fn test_view() {
+        let aabbs = random_aabbs(1000, 36);
+        let key = aabbs_key(&aabbs, &BINNED_16);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+        let mut file = Vec::new();
+        bvh.write_to(&mut file, &key).unwrap();
+
+        let storage = aligned(&file, 0);
+        let bytes = &bytemuck::cast_slice::<u128, u8>(&storage)[..file.len()];
+        let view = BVHView::<GlslBVHNode>::from_bytes(bytes, &key).unwrap();
+        // The nodes are borrowed from the file and not copied.
+        let header = std::mem::size_of::<CacheHeader>();
+        assert_eq!(view.nodes().as_ptr() as *const u8, bytes[header..].as_ptr());
+        assert_eq!(
+            bytemuck::cast_slice::<GlslBVHNode, u8>(view.nodes()),
+            bytemuck::cast_slice::<GlslBVHNode, u8>(bvh.nodes())
+        );
+        assert_eq!(view.indices(), bvh.indices());
+        assert_eq!(view.sah_cost(&BINNED_16), bvh.sah_cost(&BINNED_16));
+
+        for ray in random_rays(200, 37) {
+            let prim_test = |i: usize, ray: &Ray, tmin, tmax| {
+                aabbs[i].intersect_ray(ray, tmin, tmax).map(|t| t.0)
+            };
+            assert_eq!(
+                view.intersect_closest(&ray, 0., f32::INFINITY, prim_test),
+                bvh.intersect_closest(&ray, 0., f32::INFINITY, prim_test)
+            );
+            let prim_test =
+                |i: usize, ray: &Ray, tmax| aabbs[i].intersect_ray(ray, 0., tmax).is_some();
+            assert_eq!(
+                view.intersect_any(&ray, 10., prim_test),
+                bvh.intersect_any(&ray, 10., prim_test)
+            );
+        }
+
+        // Not aligned for the nodes.
+        let storage = aligned(&file, 4);
+        let bytes = &bytemuck::cast_slice::<u128, u8>(&storage)[4..4 + file.len()];
+        assert!(matches!(
+            BVHView::<GlslBVHNode>::from_bytes(bytes, &key),
+            Err(CacheError::Misaligned(_))
+        ));
+
+        let storage = aligned(&file, 0);
+        let bytes = &bytemuck::cast_slice::<u128, u8>(&storage)[..file.len() - 20];
+        assert!(matches!(
+            BVHView::<GlslBVHNode>::from_bytes(bytes, &key),
+            Err(CacheError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+        assert!(matches!(
+            BVHView::<CompactBVHNode>::from_bytes(&file, &key),
+            Err(CacheError::TypeMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_cache_errors() {
+        let aabbs = random_aabbs(100, 35);
+        let key = aabbs_key(&aabbs, &BINNED_8);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_8).unwrap();
+        let mut file = Vec::new();
+        bvh.write_to(&mut file, &key).unwrap();
+
+        // Other primitives or settings.
+        let mut moved = aabbs.clone();
+        moved[3].max[1] += 1.;
+        let stale = [
+            aabbs_key(&moved, &BINNED_8),
+            aabbs_key(&aabbs, &BINNED_16),
+            aabbs_key(&aabbs, &SWEEP),
+            aabbs_key(
+                &aabbs,
+                &BuildConfig {
+                    traversal_cost: 2.,
+                    ..BINNED_8
+                },
+            ),
+        ];
+        for key in stale {
+            assert!(matches!(
+                GlslBVH::read_from(file.as_slice(), &key),
+                Err(CacheError::Stale)
+            ));
+        }
+
+        assert!(matches!(
+            CompactBVH::read_from(file.as_slice(), &key),
+            Err(CacheError::TypeMismatch)
+        ));
+        assert!(matches!(
+            GlslBVH16::read_from(file.as_slice(), &key),
+            Err(CacheError::TypeMismatch)
+        ));
+
+        let mut corrupted = file.clone();
+        corrupted[0] = b'X';
+        assert!(matches!(
+            GlslBVH::read_from(corrupted.as_slice(), &key),
+            Err(CacheError::InvalidMagic)
+        ));
+
+        let mut corrupted = file.clone();
+        corrupted[8..12].copy_from_slice(&CACHE_VERSION.swap_bytes().to_ne_bytes());
+        assert!(matches!(
+            GlslBVH::read_from(corrupted.as_slice(), &key),
+            Err(CacheError::UnsupportedVersion { .. })
+        ));
+
+        assert!(matches!(
+            GlslBVH::read_from(&file[..file.len() - 20], &key),
+            Err(CacheError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+
+        // The right child of the root does not follow its left subtree.
+        let mut nodes = bvh.nodes().to_vec();
+        nodes[0].right += 1;
+        let mut corrupted = file.clone();
+        let header = std::mem::size_of::<CacheHeader>();
+        corrupted[header..header + std::mem::size_of_val(bvh.nodes())]
+            .copy_from_slice(bytemuck::cast_slice(&nodes));
+        assert!(matches!(
+            GlslBVH::read_from(corrupted.as_slice(), &key),
+            Err(CacheError::InvalidTree(_))
+        ));
+    }
+
+    ///
+    /// A valid tree whose left spine has `depth` inner nodes, each with a leaf as right child.
+    ///
+    fn left_spine(depth: usize) -> GlslBVH {
+        let aabb = AABB {
+            min: [0.; 3],
+            max: [1.; 3],
+        };
+        // The right child of the inner node k follows the leaves of the deeper nodes.
+        let right = |k: usize| 2 * depth - k;
+        let mut nodes = Vec::with_capacity(2 * depth + 1);
+        for k in 0..depth {
+            let miss = if k == 0 {
+                GlslBVHNode::MISS_SENTINEL
+            } else {
+                right(k - 1)
+            };
+            nodes.push(GlslBVHNode::new_node(aabb, right(k), miss));
+        }
+        nodes.push(GlslBVHNode::new_leaf(aabb, 0..1, right(depth - 1)));
+        for k in (0..depth).rev() {
+            let miss = if k == 0 {
+                GlslBVHNode::MISS_SENTINEL
+            } else {
+                right(k - 1)
+            };
+            nodes.push(GlslBVHNode::new_leaf(aabb, depth - k..depth - k + 1, miss));
+        }
+        GlslBVH::from_raw_parts(nodes, (0..depth + 1).collect())
+    }
+
+    #[test]
+    fn test_view_untrusted() {
+        let key = CacheKey::new(&SWEEP, b"left spine");
+        let header = std::mem::size_of::<CacheHeader>();
+        let node_size = std::mem::size_of::<GlslBVHNode>();
+
+        // Deep enough to overflow the stack of a recursive validation.
+        let depth = 100_000;
+        let bvh = left_spine(depth);
+        let mut file = Vec::new();
+        bvh.write_to(&mut file, &key).unwrap();
+        let storage = aligned(&file, 0);
+        let bytes = &bytemuck::cast_slice::<u128, u8>(&storage)[..file.len()];
+        let view = BVHView::<GlslBVHNode>::from_bytes(bytes, &key).unwrap();
+        assert_eq!(view.nodes().len(), 2 * depth + 1);
+
+        // The deepest leaf has the wrong miss pointer.
+        let mut corrupted = file.clone();
+        let offset = header + depth * node_size;
+        let mut leaf: GlslBVHNode =
+            bytemuck::pod_read_unaligned(&corrupted[offset..offset + node_size]);
+        leaf.miss += 1;
+        corrupted[offset..offset + node_size].copy_from_slice(bytemuck::bytes_of(&leaf));
+        let storage = aligned(&corrupted, 0);
+        let bytes = &bytemuck::cast_slice::<u128, u8>(&storage)[..corrupted.len()];
+        assert!(matches!(
+            BVHView::<GlslBVHNode>::from_bytes(bytes, &key),
+            Err(CacheError::InvalidTree(ValidationError::InvalidMiss { index, .. }))
+                if index == depth
+        ));
+
+        // A leaf range overflowing the address space.
+        let mut leaf: GlslBVHNode =
+            bytemuck::pod_read_unaligned(&file[offset..offset + node_size]);
+        leaf.right = u32::MAX - 1;
+        leaf.count = u32::MAX;
+        let mut corrupted = file.clone();
+        corrupted[offset..offset + node_size].copy_from_slice(bytemuck::bytes_of(&leaf));
+        let storage = aligned(&corrupted, 0);
+        let bytes = &bytemuck::cast_slice::<u128, u8>(&storage)[..corrupted.len()];
+        assert!(matches!(
+            BVHView::<GlslBVHNode>::from_bytes(bytes, &key),
+            Err(CacheError::InvalidTree(ValidationError::InvalidLeafRange { index }))
+                if index == depth
+        ));
+
+        // Truncated in the middle of the nodes.
+        let bytes = &bytemuck::cast_slice::<u128, u8>(&storage)[..header + node_size * 10];
+        assert!(matches!(
+            BVHView::<GlslBVHNode>::from_bytes(bytes, &key),
+            Err(CacheError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+
+        // Sections ending past the address space.
+        let mut corrupted = file.clone();
+        let mut cache_header: CacheHeader = bytemuck::pod_read_unaligned(&corrupted[..header]);
+        cache_header.node_count = (usize::MAX / node_size) as u64;
+        corrupted[..header].copy_from_slice(bytemuck::bytes_of(&cache_header));
+        assert!(matches!(
+            BVHView::<GlslBVHNode>::from_bytes(&corrupted, &key),
+            Err(CacheError::InvalidHeader)
+        ));
+    }
+}
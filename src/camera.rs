@@ -0,0 +1,192 @@
+use crate::aabb::*;
+use crate::glsl_bvh::*;
+use crate::triangle::*;
+use crate::Vert;
+
+///
+/// Pinhole camera used by trace.glsl.
+/// The ray through the center of the pixel (x, y) has the direction
+/// `corner + (x + 0.5) * right + (y + 0.5) * down`, where `corner` points to the top left corner
+/// of the image and `right` and `down` span one pixel.
+///
+/// The direction is evaluated with multiplications and additions only, which trace.glsl marks as
+/// `precise` so they are not contracted into fmas. Its rays are therefore bit identical to the
+/// ones of `ray`. trace.wgsl cannot prevent the contraction and only approximates them.
+///
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GlslCamera {
+    pub origin: [f32; 4],
+    pub corner: [f32; 4],
+    pub right: [f32; 4],
+    pub down: [f32; 4],
+}
+
+impl GlslCamera {
+    ///
+    /// Camera at `origin` looking at `target` with the vertical field of view `fov_y` in
+    /// radians, for an image of `size` pixels.
+    ///
+    pub fn look_at(
+        origin: [f32; 3],
+        target: [f32; 3],
+        up: [f32; 3],
+        fov_y: f32,
+        size: [u32; 2],
+    ) -> Self {
+        let forward = normalize(sub(target, origin));
+        let right = normalize(cross(forward, up));
+        let down = cross(forward, right);
+        let pixel = 2. * (fov_y / 2.).tan() / size[1] as f32;
+        let half = [size[0] as f32 / 2. * pixel, size[1] as f32 / 2. * pixel];
+        let corner =
+            [0, 1, 2].map(|axis| forward[axis] - right[axis] * half[0] - down[axis] * half[1]);
+        GlslCamera {
+            origin: [origin[0], origin[1], origin[2], 0.],
+            corner: [corner[0], corner[1], corner[2], 0.],
+            right: [right[0] * pixel, right[1] * pixel, right[2] * pixel, 0.],
+            down: [down[0] * pixel, down[1] * pixel, down[2] * pixel, 0.],
+        }
+    }
+
+    ///
+    /// The ray through the center of the pixel, evaluated exactly like in trace.glsl.
+    ///
+    pub fn ray(&self, pixel: [u32; 2]) -> Ray {
+        let x = pixel[0] as f32 + 0.5;
+        let y = pixel[1] as f32 + 0.5;
+        Ray::new(
+            [self.origin[0], self.origin[1], self.origin[2]],
+            [0, 1, 2].map(|axis| self.corner[axis] + x * self.right[axis] + y * self.down[axis]),
+        )
+    }
+}
+
+/// Value of the hits buffer of trace.glsl for pixels whose ray does not hit the mesh.
+pub const NO_HIT: u32 = u32::MAX;
+
+///
+/// CPU reference of trace.glsl.
+/// Returns the extern index of the closest triangle hit through every pixel of an image of
+/// `size` pixels in row major order, or `NO_HIT`, which is what the shader writes to its hits
+/// buffer. The extern indices of the BVH are the first of the three indices of a triangle.
+///
+/// The traversal is the one of `BVHView::intersect_closest` and the triangle test
+/// `intersect_triangle`, which the shader follows operation by operation.
+///
+pub fn trace_hits(
+    bvh: &GlslBVH,
+    verts: &[Vert],
+    indices: &[u32],
+    camera: &GlslCamera,
+    size: [u32; 2],
+) -> Vec<u32> {
+    let pos3 = |index: u32| {
+        let pos = verts[index as usize].pos;
+        [pos[0], pos[1], pos[2]]
+    };
+    (0..size[1])
+        .flat_map(|y| (0..size[0]).map(move |x| [x, y]))
+        .map(|pixel| {
+            let ray = camera.ray(pixel);
+            bvh.intersect_closest(&ray, 0., f32::INFINITY, |index, ray, tmin, tmax| {
+                let tri = [0, 1, 2].map(|i| pos3(indices[index + i]));
+                intersect_triangle(ray, tri, tmin, tmax)
+            })
+            .map_or(NO_HIT, |(index, _)| index as u32)
+        })
+        .collect()
+}
+
+#[inline]
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+#[inline]
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[inline]
+fn normalize(a: [f32; 3]) -> [f32; 3] {
+    let len = (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt();
+    [a[0] / len, a[1] / len, a[2] / len]
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::camera::*;
+    use crate::Vert;
+
+    #[test]
+    fn test_trace_hits() {
+        // Random triangles in the box [0, 10]^3.
+        let mut rng = Lcg(41);
+        let mut verts = Vec::new();
+        for _ in 0..300 {
+            let center = rng.next_vec3(10.);
+            for _ in 0..3 {
+                let offset = rng.next_vec3(2.);
+                let pos = [0, 1, 2].map(|axis| center[axis] + offset[axis] - 1.);
+                verts.push(Vert {
+                    pos: [pos[0], pos[1], pos[2], 1.],
+                    color: [1.; 4],
+                });
+            }
+        }
+        let indices: Vec<u32> = (0..verts.len() as u32).collect();
+        let tris: Vec<[[f32; 3]; 3]> = indices
+            .chunks(3)
+            .map(|tri| {
+                [0, 1, 2].map(|i| {
+                    let pos = verts[tri[i] as usize].pos;
+                    [pos[0], pos[1], pos[2]]
+                })
+            })
+            .collect();
+        let bvh = GlslBVH::build(
+            tris.iter().enumerate().map(|(i, tri)| {
+                (
+                    i * 3,
+                    tri.iter()
+                        .fold(AABB::empty(), |aabb, &v| aabb.grow(v.into())),
+                )
+            }),
+            &BINNED_16,
+        )
+        .unwrap();
+
+        let size = [40, 30];
+        let camera = GlslCamera::look_at([5., 5., -15.], [5., 5., 5.], [0., 1., 0.], 1., size);
+        // The center of the image lies between four pixels, [20, 15] is the one to the right of
+        // and below it. Looking along z with y up, right is -x.
+        let ray = camera.ray([20, 15]);
+        assert!(ray.dir[0] < 0. && ray.dir[1] < 0. && ray.dir[2] > 0.);
+        assert!(ray.dir[0].abs() < 0.02 && ray.dir[1].abs() < 0.02);
+
+        let hits = trace_hits(&bvh, &verts, &indices, &camera, size);
+        assert_eq!(hits.len(), 40 * 30);
+        assert!(hits.contains(&NO_HIT));
+        assert!(hits.iter().any(|&hit| hit != NO_HIT));
+        for (i, &hit) in hits.iter().enumerate() {
+            let ray = camera.ray([i as u32 % size[0], i as u32 / size[0]]);
+            let closest = tris
+                .iter()
+                .filter_map(|&tri| intersect_triangle(&ray, tri, 0., f32::INFINITY))
+                .map(|hit| hit.t)
+                .reduce(f32::min);
+            // Triangles hit at the same distance can be found in any order, so compare the
+            // distances.
+            let t = (hit != NO_HIT)
+                .then(|| intersect_triangle(&ray, tris[hit as usize / 3], 0., f32::INFINITY))
+                .map(|hit| hit.unwrap().t);
+            assert_eq!(t, closest, "pixel {}", i);
+        }
+    }
+}
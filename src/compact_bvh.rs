@@ -0,0 +1,363 @@
+use crate::aabb::*;
+use crate::bvh::*;
+use crate::cache::*;
+use crate::glsl_bvh::*;
+use std::ops::Range;
+
+///
+/// Node layout with quantised bounds, 32 bytes instead of the 48 of `GlslBVHNode`.
+///
+/// The bounds are stored as the min corner and the extent along every axis as 16 bit integers
+/// in units of a power of two `scale`, which is shared by the three axes:
+/// `max = min + extent * scale`.
+/// The extent is rounded up, so the decoded bounds always contain the encoded ones. Since the
+/// scale is chosen per node, the error is relative to the size of the node (below 2/65535 of
+/// its largest axis) and not to its distance from the origin.
+///
+/// For leaves `right` is the first index into the indices of the BVH and `count` the number of
+/// primitives in the leaf. `count` is 0 for inner nodes.
+///
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CompactBVHNode {
+    pub min: [f32; 3],
+    /// Extent along x in the low and along y in the high 16 bits.
+    pub extent_xy: u32,
+    /// Extent along z in the low 16 bits and the biased exponent of the scale in bits 16..24.
+    pub extent_z_exp: u32,
+    pub right: u32,
+    pub miss: u32,
+    pub count: u32,
+}
+
+impl CompactBVHNode {
+    pub const EXTENT_MAX: u32 = u16::MAX as u32;
+
+    ///
+    /// The scale of the extents for a biased exponent in 1..=254, built from the bits of the
+    /// float, which is exact in the shaders too (unlike `exp2`).
+    ///
+    #[inline]
+    fn scale(exp: u32) -> f32 {
+        f32::from_bits(exp << 23)
+    }
+
+    ///
+    /// Smallest extent such that `min + extent * scale >= max`, evaluated exactly as it is
+    /// decoded, or None if it does not fit into 16 bits.
+    ///
+    fn quantize_extent(min: f32, max: f32, scale: f32) -> Option<u32> {
+        // Dividing first avoids overflows of `max - min` for huge AABBs.
+        let mut extent = (max / scale - min / scale).ceil().max(0.);
+        if extent.is_nan() || extent > Self::EXTENT_MAX as f32 {
+            return None;
+        }
+        // The subtraction rounds to nearest, the extent might be one too small.
+        while min + extent * scale < max {
+            extent += 1.;
+        }
+        (extent <= Self::EXTENT_MAX as f32).then_some(extent as u32)
+    }
+
+    ///
+    /// Encodes the bounds of the AABB into (min, extent_xy, extent_z_exp).
+    /// Panics if the AABB is empty or not finite, which the builders and `BVH::refit` reject
+    /// (see `BuildError::NonFiniteBounds`).
+    ///
+    fn quantize(aabb: AABB) -> ([f32; 3], u32, u32) {
+        assert!(
+            aabb.min.iter().chain(aabb.max.iter()).all(|x| x.is_finite()) && !aabb.is_empty(),
+            "cannot quantise the bounds {:?}",
+            aabb
+        );
+        let size = (0..3)
+            .map(|axis| aabb.max[axis] - aabb.min[axis])
+            .fold(0f32, f32::max);
+        // Start with the largest exponent for which the largest extent might still fit.
+        let size_exp = if size > 0. { size.to_bits() >> 23 } else { 0 };
+        let mut exp = size_exp.saturating_sub(16).max(1);
+        loop {
+            let scale = Self::scale(exp);
+            let extent =
+                [0, 1, 2].map(|axis| Self::quantize_extent(aabb.min[axis], aabb.max[axis], scale));
+            if let [Some(x), Some(y), Some(z)] = extent {
+                return (aabb.min, x | (y << 16), z | (exp << 16));
+            }
+            // 65535 * 2^127 overflows, so every finite AABB fits with the largest exponent.
+            debug_assert!(exp < 254);
+            exp += 1;
+        }
+    }
+
+    #[inline]
+    pub fn extent(&self) -> [u32; 3] {
+        [
+            self.extent_xy & 0xffff,
+            self.extent_xy >> 16,
+            self.extent_z_exp & 0xffff,
+        ]
+    }
+}
+
+impl CacheType for CompactBVHNode {
+    const CACHE_TYPE_ID: u64 = 0x200;
+}
+
+impl BVHNode for CompactBVHNode {
+    type ExternIndex = usize;
+    const MAX_INDEX: usize = u32::MAX as usize - 1;
+    const MISS_SENTINEL: usize = u32::MAX as usize;
+    #[inline]
+    fn new_node(aabb: AABB, right: usize, miss: usize) -> Self {
+        let (min, extent_xy, extent_z_exp) = Self::quantize(aabb);
+        CompactBVHNode {
+            min,
+            extent_xy,
+            extent_z_exp,
+            right: u32::from_usize(right),
+            miss: u32::from_usize(miss),
+            count: 0,
+        }
+    }
+
+    #[inline]
+    fn new_leaf(aabb: AABB, range: Range<usize>, miss: usize) -> Self {
+        debug_assert!(!range.is_empty());
+        let (min, extent_xy, extent_z_exp) = Self::quantize(aabb);
+        CompactBVHNode {
+            min,
+            extent_xy,
+            extent_z_exp,
+            right: u32::from_usize(range.start),
+            miss: u32::from_usize(miss),
+            count: u32::from_usize(range.len()),
+        }
+    }
+
+    #[inline]
+    fn set_right(&mut self, right: usize) {
+        self.right = u32::from_usize(right);
+    }
+
+    #[inline]
+    fn set_miss(&mut self, miss: usize) {
+        self.miss = u32::from_usize(miss);
+    }
+
+    #[inline]
+    fn set_aabb(&mut self, aabb: AABB) {
+        (self.min, self.extent_xy, self.extent_z_exp) = Self::quantize(aabb);
+    }
+
+    #[inline]
+    fn miss(&self) -> usize {
+        self.miss as usize
+    }
+
+    #[inline]
+    fn right(&self) -> usize {
+        self.right as usize
+    }
+
+    #[inline]
+    fn is_leaf(&self) -> bool {
+        self.count != 0
+    }
+
+    #[inline]
+    fn is_node(&self) -> bool {
+        self.count == 0
+    }
+
+    #[inline]
+    fn aabb(&self) -> AABB {
+        let scale = Self::scale(self.extent_z_exp >> 16);
+        let extent = self.extent();
+        AABB {
+            min: self.min,
+            max: [0, 1, 2].map(|axis| self.min[axis] + extent[axis] as f32 * scale),
+        }
+    }
+
+    #[inline]
+    fn range(&self) -> Range<usize> {
+        let start = self.right as usize;
+        // Corrupt nodes can end past u32::MAX, `validate` rejects the range past the indices.
+        let end = start.saturating_add(self.count as usize);
+        start..end
+    }
+}
+
+pub type CompactBVH = BVH<CompactBVHNode>;
+
+impl CompactBVH {
+    ///
+    /// The indices of the BVH as u32 to be uploaded next to the nodes.
+    /// Fails if an extern index does not fit into 32 bits.
+    ///
+    pub fn glsl_indices(&self) -> Result<Vec<u32>, BuildError> {
+        glsl_indices(&self.indices)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::compact_bvh::*;
+    use crate::triangle::*;
+
+    struct Soup(Vec<[[f32; 3]; 3]>);
+
+    impl Triangles<usize> for Soup {
+        fn triangle(&self, index: usize) -> [[f32; 3]; 3] {
+            self.0[index]
+        }
+    }
+
+    #[test]
+    fn test_quantize() {
+        assert_eq!(std::mem::size_of::<CompactBVHNode>(), 32);
+
+        let mut rng = Lcg(26);
+        let mut aabbs = random_aabbs(1000, 27);
+        // Far from the origin, thin, flat and point like bounds.
+        aabbs.push(AABB {
+            min: [1e6, -1e6, 3e5],
+            max: [1e6 + 0.25, -1e6 + 1e-3, 3e5 + 1e4],
+        });
+        aabbs.push(AABB {
+            min: [-1., 2., 3.],
+            max: [1., 2., 3.],
+        });
+        aabbs.push(AABB::from([0.1, 0.2, 0.3]));
+        aabbs.push(AABB {
+            min: [-f32::MAX, -1., 0.],
+            max: [f32::MAX, 1., 0.],
+        });
+        for _ in 0..1000 {
+            let center = rng.next_vec3(1e4);
+            let size = rng.next_vec3(1.).map(|x| x.abs().powi(8) * 100.);
+            aabbs.push(AABB {
+                min: [0, 1, 2].map(|axis| center[axis] - size[axis]),
+                max: [0, 1, 2].map(|axis| center[axis] + size[axis]),
+            });
+        }
+
+        for aabb in aabbs {
+            let node = CompactBVHNode::new_node(aabb, 1, 2);
+            let decoded = node.aabb();
+            assert!(decoded.contains(&aabb), "{:?} {:?}", aabb, decoded);
+            assert_eq!(decoded.min, aabb.min);
+            let size = (0..3)
+                .map(|axis| aabb.max[axis] - aabb.min[axis])
+                .fold(0f32, f32::max);
+            for axis in 0..3 {
+                let error = decoded.max[axis] - aabb.max[axis];
+                assert!(
+                    size.is_infinite()
+                        || error <= size * 4. / 65535. + aabb.max[axis].abs() * f32::EPSILON,
+                    "{:?} {:?}",
+                    aabb,
+                    decoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_bvh() {
+        let aabbs = random_aabbs(2000, 28);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+        let compact = CompactBVH::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+        assert_eq!(compact.validate(), Ok(()));
+
+        // Same tree, the bounds only grow.
+        assert_eq!(compact.indices(), bvh.indices());
+        assert_eq!(compact.nodes().len(), bvh.nodes().len());
+        for (node, compact_node) in bvh.nodes().iter().zip(compact.nodes()) {
+            assert!(compact_node.aabb().contains(&node.aabb()));
+            assert_eq!(compact_node.right(), node.right());
+            assert_eq!(compact_node.miss(), node.miss());
+            assert_eq!(compact_node.is_leaf(), node.is_leaf());
+        }
+
+        for ray in random_rays(500, 29) {
+            let expected = bvh
+                .intersect_closest(&ray, 0., f32::INFINITY, |i, ray, tmin, tmax| {
+                    aabbs[i].intersect_ray(ray, tmin, tmax).map(|t| t.0)
+                })
+                .map(|hit| hit.0);
+            let hit = compact
+                .intersect_closest(&ray, 0., f32::INFINITY, |i, ray, tmin, tmax| {
+                    aabbs[i].intersect_ray(ray, tmin, tmax).map(|t| t.0)
+                })
+                .map(|hit| hit.0);
+            assert_eq!(hit, expected);
+        }
+    }
+
+    #[test]
+    fn test_compact_bvh_strategies() {
+        let mut rng = Lcg(30);
+        let soup = Soup(
+            (0..500)
+                .map(|_| {
+                    let a = rng.next_vec3(10.);
+                    let b = rng.next_vec3(1.);
+                    let c = rng.next_vec3(1.);
+                    [
+                        a,
+                        [a[0] + b[0], a[1] + b[1], a[2]],
+                        [a[0], a[1] + c[1], a[2] + c[2]],
+                    ]
+                })
+                .collect(),
+        );
+        for strategy in [
+            BuildStrategy::FullSweep,
+            BuildStrategy::Binned { bins: 8 },
+            BuildStrategy::Morton,
+            BuildStrategy::Spatial { bins: 16 },
+        ] {
+            let config = SWEEP.with(strategy);
+            let compact = CompactBVH::build_triangles(&soup, 0..soup.0.len(), &config).unwrap();
+            assert_eq!(compact.validate(), Ok(()), "{:?}", strategy);
+            let indices = compact.glsl_indices().unwrap();
+            assert!(indices
+                .iter()
+                .map(|&index| index as usize)
+                .eq(compact.indices().iter().copied()));
+        }
+    }
+
+    #[test]
+    fn test_compact_bvh_invalid() {
+        let mut aabbs = random_aabbs(100, 31);
+        let mut compact = CompactBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        let nodes = compact.nodes().to_vec();
+
+        // Refitting to NaN bounds fails and leaves the tree as it was.
+        aabbs[5].max[1] = f32::NAN;
+        let position = compact.indices().iter().position(|&i| i == 5).unwrap();
+        assert_eq!(
+            compact.refit(|i| aabbs[i]),
+            Err(BuildError::NonFiniteBounds { index: position })
+        );
+        assert!(compact
+            .nodes()
+            .iter()
+            .zip(&nodes)
+            .all(|(a, b)| bytemuck::bytes_of(a) == bytemuck::bytes_of(b)));
+
+        // A leaf range overflowing u32.
+        let mut nodes = nodes;
+        let index = nodes.iter().position(|node| node.is_leaf()).unwrap();
+        nodes[index].right = u32::MAX;
+        nodes[index].count = u32::MAX;
+        let corrupt = CompactBVH::from_raw_parts(nodes, compact.indices().to_vec());
+        assert_eq!(
+            corrupt.validate(),
+            Err(ValidationError::InvalidLeafRange { index })
+        );
+    }
+}
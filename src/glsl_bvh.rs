@@ -1,73 +1,345 @@
-
 use crate::bvh::*;
 use crate::aabb::*;
+use crate::cache::*;
+use crate::tlas::*;
+use std::ops::Range;
+
+///
+/// Unsigned integer type used for the indices of `GlslNode` and the index buffer of the BVH.
+///
+pub trait GlslIndex: bytemuck::Pod + std::fmt::Debug + TryFrom<usize> + TryInto<usize> {
+    /// Largest value of the type.
+    const MAX: usize;
+
+    ///
+    /// Converts an index that is known to fit, which the builders ensure through
+    /// `BVHNode::MAX_INDEX`.
+    /// Panics if the index does not fit into the type. Only call it for node pointers and leaf
+    /// ranges checked against `MAX_INDEX`, extern indices go through `glsl_indices`, which
+    /// returns `IndexOutOfRange` instead.
+    ///
+    #[inline]
+    fn from_usize(index: usize) -> Self {
+        match Self::try_from(index) {
+            Ok(index) => index,
+            Err(_) => panic!("index {} exceeds the index type of the node", index),
+        }
+    }
+
+    #[inline]
+    fn to_usize(self) -> usize {
+        match self.try_into() {
+            Ok(index) => index,
+            Err(_) => panic!("index {:?} exceeds the address space", self),
+        }
+    }
+}
+
+impl GlslIndex for u16 {
+    const MAX: usize = u16::MAX as usize;
+}
+impl GlslIndex for u32 {
+    const MAX: usize = u32::MAX as usize;
+}
+impl GlslIndex for u64 {
+    const MAX: usize = u64::MAX as usize;
+}
 
+///
+/// Node layout used in the shaders, generic over the width of its indices.
+/// For leaves `right` is the first index into the indices of the BVH and `count` the number of
+/// primitives in the leaf. `count` is 0 for inner nodes.
+///
+/// The shaders declare the 32 bit version (`GlslBVHNode`). 16 and 64 bit indices need the
+/// `GL_EXT_shader_16bit_storage` and `GL_EXT_shader_explicit_arithmetic_types_int64` extensions
+/// and are not available in WGSL.
+///
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct GlslBVHNode {
+#[derive(Copy, Clone, Debug)]
+pub struct GlslNode<I: GlslIndex> {
     pub min: [f32; 4],
     pub max: [f32; 4],
-    pub ty: u32,
-    pub right: u32,
-    pub miss: u32,
+    pub ty: I,
+    pub right: I,
+    pub miss: I,
+    pub count: I,
 }
-impl GlslBVHNode {
+
+// Safety: the bounds take 32 bytes and are followed by four indices of the same type, whose
+// alignment is at most 8, so there is no padding for any index type.
+unsafe impl<I: GlslIndex> bytemuck::Zeroable for GlslNode<I> {}
+unsafe impl<I: GlslIndex> bytemuck::Pod for GlslNode<I> {}
+
+impl<I: GlslIndex + CacheType> CacheType for GlslNode<I> {
+    const CACHE_TYPE_ID: u64 = 0x100 | I::CACHE_TYPE_ID;
+}
+
+pub type GlslBVHNode = GlslNode<u32>;
+pub type GlslBVHNode16 = GlslNode<u16>;
+pub type GlslBVHNode64 = GlslNode<u64>;
+
+impl<I: GlslIndex> GlslNode<I> {
     pub const TY_NODE: u32 = 0x00;
     pub const TY_LEAF: u32 = 0x01;
 }
-impl BVHNode for GlslBVHNode{
+impl<I: GlslIndex> BVHNode for GlslNode<I>{
+    // Extern indices are not stored in the nodes but in the indices of the BVH, which stay usize
+    // so that they can index the primitives on the CPU and TLASes can be built over any node
+    // type. They are converted to I when uploading (see `BVH::glsl_indices`).
     type ExternIndex = usize;
+    const MAX_INDEX: usize = I::MAX - 1;
+    const MISS_SENTINEL: usize = I::MAX;
     #[inline]
     fn new_node(aabb: AABB, right: usize, miss: usize) -> Self {
-        GlslBVHNode{
-            ty: Self::TY_NODE,
+        GlslNode{
+            ty: I::from_usize(Self::TY_NODE as usize),
             min: [aabb.min[0], aabb.min[1], aabb.min[2], 0.],
             max: [aabb.max[0], aabb.max[1], aabb.max[2], 0.],
-            right: right as u32,
-            miss: miss as u32,
+            right: I::from_usize(right),
+            miss: I::from_usize(miss),
+            count: I::from_usize(0),
         }
     }
 
     #[inline]
-    fn new_leaf(aabb: AABB, index: usize, miss: usize) -> Self {
-        GlslBVHNode{
-            ty: Self::TY_LEAF,
+    fn new_leaf(aabb: AABB, range: Range<usize>, miss: usize) -> Self {
+        GlslNode{
+            ty: I::from_usize(Self::TY_LEAF as usize),
             min: [aabb.min[0], aabb.min[1], aabb.min[2], 0.],
             max: [aabb.max[0], aabb.max[1], aabb.max[2], 0.],
-            right: index as u32,
-            miss: miss as u32,
+            right: I::from_usize(range.start),
+            miss: I::from_usize(miss),
+            count: I::from_usize(range.len()),
         }
     }
 
     #[inline]
     fn set_right(&mut self, right: usize) {
-        self.right = right as u32;
+        self.right = I::from_usize(right);
     }
 
     #[inline]
     fn set_miss(&mut self, miss: usize) {
-        self.miss = miss as u32;
+        self.miss = I::from_usize(miss);
+    }
+
+    #[inline]
+    fn set_aabb(&mut self, aabb: AABB) {
+        self.min = [aabb.min[0], aabb.min[1], aabb.min[2], 0.];
+        self.max = [aabb.max[0], aabb.max[1], aabb.max[2], 0.];
     }
 
     #[inline]
     fn miss(&self) -> usize {
-        self.miss as usize
+        self.miss.to_usize()
     }
 
     #[inline]
     fn right(&self) -> usize {
-        self.right as usize
+        self.right.to_usize()
     }
 
     #[inline]
     fn is_leaf(&self) -> bool {
-        self.ty == Self::TY_LEAF
+        self.ty.to_usize() == Self::TY_LEAF as usize
     }
 
     #[inline]
     fn is_node(&self) -> bool {
-        self.ty == Self::TY_NODE
+        self.ty.to_usize() == Self::TY_NODE as usize
+    }
+
+    #[inline]
+    fn aabb(&self) -> AABB {
+        AABB {
+            min: [self.min[0], self.min[1], self.min[2]],
+            max: [self.max[0], self.max[1], self.max[2]],
+        }
+    }
+
+    #[inline]
+    fn range(&self) -> Range<usize> {
+        let start = self.right.to_usize();
+        // Corrupt nodes can end past usize::MAX, `validate` rejects the range past the indices.
+        start..start.saturating_add(self.count.to_usize())
     }
 }
 
 pub type GlslBVH = BVH<GlslBVHNode>;
+pub type GlslBVH16 = BVH<GlslBVHNode16>;
+pub type GlslBVH64 = BVH<GlslBVHNode64>;
+
+impl<I: GlslIndex> BVH<GlslNode<I>> {
+    ///
+    /// The indices of the BVH converted to the index type of the nodes to be uploaded next to
+    /// them. Fails if an extern index does not fit into the index type.
+    ///
+    pub fn glsl_indices(&self) -> Result<Vec<I>, BuildError> {
+        glsl_indices(&self.indices)
+    }
+}
+
+///
+/// Converts extern indices to the index type of the shader buffers.
+///
+pub(crate) fn glsl_indices<I: GlslIndex>(indices: &[usize]) -> Result<Vec<I>, BuildError> {
+    indices
+        .iter()
+        .map(|&index| I::try_from(index).map_err(|_| BuildError::IndexOutOfRange { index }))
+        .collect()
+}
+
+///
+/// Instance layout used in the shaders.
+/// The nodes and indices of all BLASes are concatenated into single buffers, the pointers and
+/// ranges inside of a BLAS are relative to `node_offset` and `index_offset`, except for
+/// `MISS_SENTINEL`.
+///
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GlslInstance {
+    /// Rows of the transform from world space into the space of the BLAS.
+    pub world_to_object: [[f32; 4]; 3],
+    pub node_offset: u32,
+    pub index_offset: u32,
+    pub mask: u32,
+    pub id: u32,
+}
+
+pub type GlslTLAS = TLAS<GlslBVHNode>;
+
+///
+/// Everything needed to trace a TLAS in a shader.
+/// trace.glsl with `TRACE_TLAS` binds `nodes`, `indices` and `instances` at the bindings 4 to 6
+/// and `blas_nodes` and `blas_indices` in place of the BVH of the mesh, see `TraceTLAS`.
+///
+pub struct GlslTLASBuffers {
+    pub nodes: Vec<GlslBVHNode>,
+    /// Maps the indices of the TLAS leaves to instances.
+    pub indices: Vec<u32>,
+    pub instances: Vec<GlslInstance>,
+    pub blas_nodes: Vec<GlslBVHNode>,
+    pub blas_indices: Vec<u32>,
+}
+
+impl GlslTLAS {
+    ///
+    /// Concatenates the BLASes and creates the instances pointing into them.
+    /// `blases` has to be the slice the TLAS was built with.
+    /// Fails if an instance references a BLAS outside of `blases` or if the concatenated buffers
+    /// cannot be indexed with 32 bits.
+    ///
+    pub fn glsl_buffers(&self, blases: &[GlslBVH]) -> Result<GlslTLASBuffers, BuildError> {
+        let mut blas_nodes = Vec::new();
+        let mut blas_indices = Vec::new();
+        let mut offsets = Vec::with_capacity(blases.len());
+        for blas in blases {
+            offsets.push((blas_nodes.len() as u32, blas_indices.len() as u32));
+            blas_nodes.extend_from_slice(blas.nodes());
+            blas_indices.extend(blas.glsl_indices()?);
+            let count = blas_nodes.len().max(blas_indices.len());
+            if count > GlslBVHNode::MAX_INDEX {
+                return Err(BuildError::TooManyPrimitives { count });
+            }
+        }
+        let instances = self
+            .instances
+            .iter()
+            .zip(self.world_to_object())
+            .enumerate()
+            .map(|(index, (instance, world_to_object))| {
+                let (node_offset, index_offset) = *offsets
+                    .get(instance.blas)
+                    .ok_or(BuildError::InvalidInstance { index })?;
+                Ok(GlslInstance {
+                    world_to_object: *world_to_object,
+                    node_offset,
+                    index_offset,
+                    mask: instance.mask,
+                    id: instance.id,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(GlslTLASBuffers {
+            nodes: self.bvh.nodes().to_vec(),
+            indices: self.bvh.glsl_indices()?,
+            instances,
+            blas_nodes,
+            blas_indices,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::glsl_bvh::*;
+
+    #[test]
+    fn test_index_widths() {
+        assert_eq!(std::mem::size_of::<GlslBVHNode16>(), 40);
+        assert_eq!(std::mem::size_of::<GlslBVHNode>(), 48);
+        assert_eq!(std::mem::size_of::<GlslBVHNode64>(), 64);
+
+        let aabbs = random_aabbs(1000, 31);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+        let bvh16 = GlslBVH16::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+        let bvh64 = GlslBVH64::build(aabbs.iter().copied().enumerate(), &BINNED_16).unwrap();
+        assert_eq!(bvh16.validate(), Ok(()));
+        assert_eq!(bvh64.validate(), Ok(()));
+        assert_eq!(bvh16.nodes().len(), bvh.nodes().len());
+        assert_eq!(bvh64.nodes().len(), bvh.nodes().len());
+        for ((node, node16), node64) in bvh.nodes().iter().zip(bvh16.nodes()).zip(bvh64.nodes()) {
+            assert_eq!(node16.is_leaf(), node.is_leaf());
+            assert_eq!(node64.is_leaf(), node.is_leaf());
+            assert_eq!(node16.right as u32, node.right);
+            assert_eq!(node64.right as u32, node.right);
+            if node.miss() == GlslBVHNode::MISS_SENTINEL {
+                assert_eq!(node16.miss, u16::MAX);
+                assert_eq!(node64.miss, u64::MAX);
+            } else {
+                assert_eq!(node16.miss as u32, node.miss);
+                assert_eq!(node64.miss as u32, node.miss);
+            }
+        }
+        assert_eq!(
+            bvh16.glsl_indices().unwrap(),
+            bvh.indices().iter().map(|&i| i as u16).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            bvh64.glsl_indices().unwrap(),
+            bvh.indices().iter().map(|&i| i as u64).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_index_overflow() {
+        // More primitives than 16 bit node indices can address.
+        let aabbs = random_aabbs(40_000, 32);
+        assert_eq!(
+            GlslBVH16::build(aabbs.iter().copied().enumerate(), &BINNED_8).unwrap_err(),
+            BuildError::TooManyPrimitives { count: 40_000 }
+        );
+
+        // Few primitives with extern indices out of range.
+        let aabbs = random_aabbs(10, 33);
+        let bvh16 = GlslBVH16::build(
+            aabbs
+                .iter()
+                .enumerate()
+                .map(|(i, &aabb)| (i * 10_000, aabb)),
+            &BINNED_8,
+        )
+        .unwrap();
+        let index = bvh16
+            .indices()
+            .iter()
+            .copied()
+            .find(|&i| i > u16::MAX as usize)
+            .unwrap();
+        assert_eq!(
+            bvh16.glsl_indices().unwrap_err(),
+            BuildError::IndexOutOfRange { index }
+        );
+    }
+}
@@ -2,12 +2,25 @@ use std::ops::Range;
 
 mod aabb;
 mod bvh;
+mod cache;
+mod camera;
+mod compact_bvh;
 mod glsl_bvh;
+mod morton;
+mod optimize;
+mod parallel;
+mod sbvh;
+mod shader_layout;
+mod tlas;
 mod trace_ppl;
+mod triangle;
+mod wide_bvh;
 
 use aabb::*;
 use bvh::*;
+use camera::*;
 use glsl_bvh::*;
+use triangle::*;
 
 pub trait Pos3 {
     fn pos3(&self) -> [f32; 3];
@@ -20,6 +33,11 @@ pub struct Vert {
     pub color: [f32; 4],
 }
 
+shader_layout::impl_shader_layout!(Vert, "Vert", {
+    pos: Vec4F32,
+    color: Vec4F32,
+});
+
 impl Pos3 for Vert {
     fn pos3(&self) -> [f32; 3] {
         [self.pos[0], self.pos[1], self.pos[2]]
@@ -55,8 +73,8 @@ impl Mesh {
     pub fn get_tri(&self, index: usize) -> [Vert; 3] {
         [
             self.verts[self.indices[index] as usize],
-            self.verts[self.indices[index] as usize],
-            self.verts[self.indices[index] as usize],
+            self.verts[self.indices[index + 1] as usize],
+            self.verts[self.indices[index + 2] as usize],
         ]
     }
     pub fn get_for_tri(&self, indices: &[usize; 3]) -> [Vert; 3] {
@@ -66,6 +84,27 @@ impl Mesh {
             self.verts[indices[2]],
         ]
     }
+    ///
+    /// Intersects the ray with the triangle starting at `index` in the indices.
+    /// This can be used as the primitive test when traversing a BVH built over the mesh.
+    ///
+    pub fn intersect_tri(
+        &self,
+        index: usize,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+    ) -> Option<TriangleHit> {
+        let tri = self.get_tri(index);
+        intersect_triangle(ray, [tri[0].pos3(), tri[1].pos3(), tri[2].pos3()], tmin, tmax)
+    }
+}
+
+impl Triangles<usize> for Mesh {
+    fn triangle(&self, index: usize) -> [[f32; 3]; 3] {
+        let tri = self.get_tri(index);
+        [tri[0].pos3(), tri[1].pos3(), tri[2].pos3()]
+    }
 }
 
 fn main() {
@@ -111,11 +150,15 @@ fn main() {
 
     let mesh = Mesh { verts, indices };
 
-    let bvh = GlslBVH::build_sweep(
-        (0..mesh.indices.len() / 3)
-            .into_iter()
-            .map(|i| IndexedAABB{ index: i * 3, aabb: mesh.get_tri(i * 3).into()}),
-    );
+    let bvh = GlslBVH::build_triangles(
+        &mesh,
+        (0..mesh.indices.len() / 3).map(|i| i * 3),
+        &BuildConfig {
+            strategy: BuildStrategy::Spatial { bins: 16 },
+            ..Default::default()
+        },
+    )
+    .unwrap();
     bvh.print_rec(0, &mut String::from(""));
 
     let suzanne = tobj::load_obj("src/assets/suzanne.obj", &tobj::LoadOptions::default())
@@ -147,10 +190,15 @@ fn main() {
 
     let mesh = Mesh { verts, indices };
 
-    let bvh = GlslBVH::build_buckets_16(
+    let bvh = GlslBVH::build_par(
         (0..mesh.indices.len() / 3)
             .into_iter()
             .map(|i| IndexedAABB{ index: i * 3, aabb: mesh.get_tri(i * 3).into()}),
-    );
+        &BuildConfig {
+            strategy: BuildStrategy::Binned { bins: 16 },
+            ..Default::default()
+        },
+    )
+    .unwrap();
     //bvh.print_rec(0, &mut String::from(""));
 }
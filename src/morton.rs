@@ -0,0 +1,257 @@
+use crate::aabb::*;
+use crate::bvh::*;
+
+///
+/// Primitive counts up to which 30 bit morton codes (10 bits per axis) are used.
+/// Larger inputs use 63 bit codes (21 bits per axis) to keep the number of equal codes low.
+///
+const MORTON_30_MAX_PRIMITIVES: usize = 1 << 20;
+
+///
+/// Spreads the lowest `bits` bits of `x` such that there are two zero bits between every bit.
+///
+#[inline]
+fn spread_bits(x: u64, bits: u32) -> u64 {
+    let mut result = 0;
+    for i in 0..bits {
+        result |= ((x >> i) & 1) << (3 * i);
+    }
+    result
+}
+
+///
+/// Interleaves the quantised coordinates into a morton code with `bits` bits per axis.
+///
+#[inline]
+pub fn morton_code(quantised: [u64; 3], bits: u32) -> u64 {
+    (spread_bits(quantised[0], bits) << 2)
+        | (spread_bits(quantised[1], bits) << 1)
+        | spread_bits(quantised[2], bits)
+}
+
+///
+/// Quantises the point relative to `bounds` into `bits` bits per axis.
+///
+#[inline]
+pub fn quantise(point: [f32; 3], bounds: &AABB, bits: u32) -> [u64; 3] {
+    let max = ((1u64 << bits) - 1) as f32;
+    [0, 1, 2].map(|axis| {
+        let extent = bounds.max[axis] - bounds.min[axis];
+        if extent > 0. {
+            ((point[axis] - bounds.min[axis]) / extent * max).clamp(0., max) as u64
+        } else {
+            0
+        }
+    })
+}
+
+///
+/// Stable LSD radix sort of the (code, index) pairs by the lowest `bits` bits of the code using
+/// 8 bit digits.
+///
+pub fn radix_sort(keys: &mut Vec<(u64, usize)>, bits: u32) {
+    let mut tmp = vec![(0, 0); keys.len()];
+    let mut shift = 0;
+    while shift < bits {
+        let mut counts = [0usize; 257];
+        for key in keys.iter() {
+            counts[((key.0 >> shift) & 0xff) as usize + 1] += 1;
+        }
+        for i in 0..256 {
+            counts[i + 1] += counts[i];
+        }
+        for key in keys.iter() {
+            let digit = ((key.0 >> shift) & 0xff) as usize;
+            tmp[counts[digit]] = *key;
+            counts[digit] += 1;
+        }
+        std::mem::swap(keys, &mut tmp);
+        shift += 8;
+    }
+}
+
+impl<Node: BVHNode> BVH<Node> {
+    ///
+    /// Linear BVH builder.
+    /// The centroids of the children are quantised into morton codes and radix sorted, which
+    /// orders them along a z-order curve. The hierarchy is then emitted by splitting every range
+    /// at the highest bit in which the codes of its children differ, as described by Karras:
+    /// https://research.nvidia.com/sites/default/files/pubs/2012-06_Maximizing-Parallelism-in/karras2012hpg_paper.pdf
+    ///
+    /// Nodes are written in the same pre-order layout with pivots in the miss pointers as the
+    /// other builders. Leaves are created for ranges of at most `max_leaf_size` children.
+    ///
+    /// Returns the children in the order in which the leaves reference them.
+    ///
+    pub(crate) fn morton_build(
+        dst: &mut Vec<Node>,
+        config: &BuildConfig,
+        children: &[IndexedAABB<Node::ExternIndex>],
+    ) -> Vec<IndexedAABB<Node::ExternIndex>> {
+        let centroid_aabb = children
+            .iter()
+            .map(|c| c.aabb.centroid().into())
+            .fold(AABB::empty(), AABB::grow);
+        let bits = if children.len() <= MORTON_30_MAX_PRIMITIVES {
+            10
+        } else {
+            21
+        };
+
+        let mut keys: Vec<(u64, usize)> = children
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                (
+                    morton_code(quantise(c.aabb.centroid(), &centroid_aabb, bits), bits),
+                    i,
+                )
+            })
+            .collect();
+        radix_sort(&mut keys, 3 * bits);
+
+        let codes: Vec<u64> = keys.iter().map(|k| k.0).collect();
+        let children: Vec<IndexedAABB<Node::ExternIndex>> =
+            keys.iter().map(|k| children[k.1]).collect();
+
+        Self::morton_pivot(dst, config, &codes, &children, 0..children.len(), 0);
+        children
+    }
+
+    ///
+    /// Emits the subtree over `children[range]` and returns its index and AABB.
+    /// See `sweep_pivot` for the meaning of `pivot`.
+    ///
+    fn morton_pivot(
+        dst: &mut Vec<Node>,
+        config: &BuildConfig,
+        codes: &[u64],
+        children: &[IndexedAABB<Node::ExternIndex>],
+        range: std::ops::Range<usize>,
+        pivot: usize,
+    ) -> (usize, AABB) {
+        if range.len() <= config.max_leaf_size.max(1) {
+            let aabb = children[range.clone()]
+                .iter()
+                .map(|c| c.aabb)
+                .fold(AABB::empty(), AABB::grow);
+            dst.push(Node::new_leaf(aabb, range, pivot));
+            return (dst.len() - 1, aabb);
+        }
+
+        let split = Self::morton_split(codes, range.start, range.end - 1) + 1;
+
+        let node_i = dst.len();
+        // The bounds are only known once the children are built. Node types quantising their
+        // bounds need a finite AABB, so start with the one of a child.
+        dst.push(Node::new_node(children[range.start].aabb, 0, pivot));
+        let (_l_node_i, l_aabb) =
+            Self::morton_pivot(dst, config, codes, children, range.start..split, node_i);
+        let (r_node_i, r_aabb) =
+            Self::morton_pivot(dst, config, codes, children, split..range.end, pivot);
+        let aabb = l_aabb.grow(r_aabb);
+        dst[node_i].set_right(r_node_i);
+        dst[node_i].set_aabb(aabb);
+        (node_i, aabb)
+    }
+
+    ///
+    /// Finds the last index of the left child of the range [first, last] by binary searching
+    /// for the highest index that shares more leading bits with `first` than `last` does.
+    /// Ranges with identical codes are split in the middle.
+    ///
+    fn morton_split(codes: &[u64], first: usize, last: usize) -> usize {
+        let first_code = codes[first];
+        let last_code = codes[last];
+        if first_code == last_code {
+            return (first + last) >> 1;
+        }
+        let common_prefix = (first_code ^ last_code).leading_zeros();
+
+        let mut split = first;
+        let mut step = last - first;
+        loop {
+            step = (step + 1) >> 1;
+            let new_split = split + step;
+            if new_split < last {
+                let split_prefix = (first_code ^ codes[new_split]).leading_zeros();
+                if split_prefix > common_prefix {
+                    split = new_split;
+                }
+            }
+            if step <= 1 {
+                break;
+            }
+        }
+        split
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::glsl_bvh::*;
+    use crate::morton::*;
+
+    #[test]
+    fn test_morton_code() {
+        assert_eq!(morton_code([1, 0, 0], 10), 0b100);
+        assert_eq!(morton_code([0, 1, 0], 10), 0b010);
+        assert_eq!(morton_code([0, 0, 1], 10), 0b001);
+        assert_eq!(morton_code([3, 3, 3], 10), 0b111111);
+        assert_eq!(morton_code([1023, 1023, 1023], 10), (1 << 30) - 1);
+        assert_eq!(
+            morton_code([(1 << 21) - 1, (1 << 21) - 1, (1 << 21) - 1], 21),
+            (1 << 63) - 1
+        );
+    }
+
+    #[test]
+    fn test_radix_sort() {
+        let mut rng = Lcg(12);
+        let mut keys: Vec<(u64, usize)> = (0..1000)
+            .map(|i| (((rng.next_f32() * 1e6) as u64) << 20, i))
+            .collect();
+        let mut expected = keys.clone();
+        expected.sort_by_key(|k| k.0);
+        radix_sort(&mut keys, 63);
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_morton_build() {
+        let aabbs = random_aabbs(1000, 13);
+        for max_leaf_size in [1, 4] {
+            let config = BuildConfig {
+                strategy: BuildStrategy::Morton,
+                max_leaf_size,
+                ..Default::default()
+            };
+            let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &config).unwrap();
+            assert_eq!(bvh.validate(), Ok(()));
+
+            for ray in random_rays(200, 14) {
+                let expected = aabbs
+                    .iter()
+                    .filter_map(|aabb| aabb.intersect_ray(&ray, 0., f32::INFINITY))
+                    .map(|t| t.0)
+                    .min_by(|a, b| a.total_cmp(b));
+                let hit = bvh
+                    .intersect_closest(&ray, 0., f32::INFINITY, |i, ray, tmin, tmax| {
+                        aabbs[i].intersect_ray(ray, tmin, tmax).map(|t| t.0)
+                    })
+                    .map(|hit| hit.1);
+                assert_eq!(hit, expected);
+            }
+        }
+
+        // Identical centroids result in identical codes.
+        let aabbs = vec![AABB::from([1., 1., 1.]); 33];
+        let bvh = GlslBVH::build(
+            aabbs.iter().copied().enumerate(),
+            &SWEEP.with(BuildStrategy::Morton),
+        )
+        .unwrap();
+        assert_eq!(bvh.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::aabb::*;
+use crate::bvh::*;
+
+///
+/// Result of an optimisation pass, used to decide whether a full rebuild would be worth it.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OptimizeReport {
+    /// SAH cost of the tree before the optimisation (see `BVH::sah_cost`).
+    pub sah_before: f32,
+    /// SAH cost of the tree after the optimisation.
+    pub sah_after: f32,
+    /// Number of rotations applied.
+    pub rotations: usize,
+    /// Number of passes over the tree, including the last one that found no more rotations.
+    pub passes: usize,
+}
+
+///
+/// The tree with explicit child pointers, indexed by the position of the nodes before the
+/// optimisation. The root stays at index 0.
+///
+struct RotationTree {
+    children: Vec<Option<[usize; 2]>>,
+    aabbs: Vec<AABB>,
+}
+
+impl RotationTree {
+    ///
+    /// Applies the best rotation at `index` that lowers the surface area of one of its children
+    /// and returns true if there was one.
+    ///
+    /// A rotation swaps one child of the node with a child of its sibling:
+    ///
+    ///     N              N
+    ///    / \            / \
+    ///   A   S   ->     C   S'
+    ///      / \            / \
+    ///     C   D          A   D
+    ///
+    /// Only the AABB of S changes, so the SAH cost changes by C_trav * (SA(S') - SA(S)).
+    ///
+    fn rotate(&mut self, index: usize) -> bool {
+        let [l, r] = match self.children[index] {
+            Some(children) => children,
+            None => return false,
+        };
+        // (side of the swapped child of N, side of the swapped child of S, new AABB of S)
+        let mut best: Option<(usize, usize, AABB)> = None;
+        let mut best_sa = f32::INFINITY;
+        for (side, a, s) in [(0, l, r), (1, r, l)] {
+            let s_children = match self.children[s] {
+                Some(children) => children,
+                None => continue,
+            };
+            let s_sa = self.aabbs[s].surface_area();
+            for s_side in 0..2 {
+                let d = s_children[1 - s_side];
+                let aabb = self.aabbs[a].grow(self.aabbs[d]);
+                let sa = aabb.surface_area();
+                if sa < s_sa && sa - s_sa < best_sa {
+                    best_sa = sa - s_sa;
+                    best = Some((side, s_side, aabb));
+                }
+            }
+        }
+
+        let (side, s_side, aabb) = match best {
+            Some(best) => best,
+            None => return false,
+        };
+        let mut n_children = [l, r];
+        let a = n_children[side];
+        let s = n_children[1 - side];
+        let mut s_children = self.children[s].unwrap();
+        n_children[side] = s_children[s_side];
+        s_children[s_side] = a;
+        self.children[index] = Some(n_children);
+        self.children[s] = Some(s_children);
+        self.aabbs[s] = aabb;
+        true
+    }
+    ///
+    /// Rotates the subtree bottom up and returns the number of rotations.
+    ///
+    fn rotate_rec(&mut self, index: usize) -> usize {
+        let children = match self.children[index] {
+            Some(children) => children,
+            None => return 0,
+        };
+        let rotations = self.rotate_rec(children[0]) + self.rotate_rec(children[1]);
+        rotations + self.rotate(index) as usize
+    }
+}
+
+impl<Node: BVHNode> BVH<Node> {
+    ///
+    /// Improves the tree with tree rotations as described by Kensler:
+    /// https://www.cs.utah.edu/docs/techreports/2008/pdf/UUCS-08-010.pdf
+    ///
+    /// Every pass walks the tree bottom up and applies the rotation that reduces the SAH cost
+    /// the most at every node. Passes are repeated until no rotation is found or `max_passes`
+    /// is reached. The leaves are not changed, only the structure above them.
+    /// Afterwards the nodes and indices are re-emitted in pre-order with new miss pointers.
+    ///
+    /// This is mainly useful for trees that have been refitted many times or were built with a
+    /// fast builder. Compare the reported SAH cost with the one of a fresh build to decide when a
+    /// rebuild is necessary.
+    ///
+    pub fn optimize(&mut self, config: &BuildConfig, max_passes: usize) -> OptimizeReport {
+        let sah_before = self.sah_cost(config);
+        let mut tree = RotationTree {
+            children: self
+                .nodes
+                .iter()
+                .enumerate()
+                .map(|(i, node)| node.is_node().then(|| [i + 1, node.right()]))
+                .collect(),
+            aabbs: self.nodes.iter().map(|node| node.aabb()).collect(),
+        };
+
+        let mut rotations = 0;
+        let mut passes = 0;
+        while passes < max_passes {
+            passes += 1;
+            let pass_rotations = tree.rotate_rec(0);
+            rotations += pass_rotations;
+            if pass_rotations == 0 {
+                break;
+            }
+        }
+
+        if rotations > 0 {
+            let mut nodes = Vec::with_capacity(self.nodes.len());
+            let mut indices = Vec::with_capacity(self.indices.len());
+            self.emit_rotated(&tree, 0, &mut nodes, &mut indices, 0);
+            self.nodes = nodes;
+            self.indices = indices;
+            self.pivot_to_miss();
+            self.enclose_children();
+            debug_assert_eq!(self.validate(), Ok(()));
+        }
+
+        OptimizeReport {
+            sah_before,
+            sah_after: self.sah_cost(config),
+            rotations,
+            passes,
+        }
+    }
+    ///
+    /// Writes the rotated subtree in pre-order with pivots in the miss pointers, the same way as
+    /// the builders do, and moves the indices of the leaves along.
+    ///
+    fn emit_rotated(
+        &self,
+        tree: &RotationTree,
+        index: usize,
+        dst: &mut Vec<Node>,
+        indices: &mut Vec<Node::ExternIndex>,
+        pivot: usize,
+    ) -> usize {
+        let aabb = tree.aabbs[index];
+        match tree.children[index] {
+            None => {
+                let start = indices.len();
+                indices.extend_from_slice(&self.indices[self.nodes[index].range()]);
+                dst.push(Node::new_leaf(aabb, start..indices.len(), pivot));
+                dst.len() - 1
+            }
+            Some([l, r]) => {
+                let node_i = dst.len();
+                dst.push(Node::new_node(aabb, 0, pivot));
+                self.emit_rotated(tree, l, dst, indices, node_i);
+                let r_node_i = self.emit_rotated(tree, r, dst, indices, pivot);
+                dst[node_i].set_right(r_node_i);
+                node_i
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::glsl_bvh::*;
+    use crate::optimize::*;
+
+    #[test]
+    fn test_optimize() {
+        let aabbs = random_aabbs(2000, 24);
+        // Morton trees leave a lot of room for improvement.
+        let config = SWEEP.with(BuildStrategy::Morton);
+        let mut bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &config).unwrap();
+        let sah = bvh.sah_cost(&config);
+
+        let report = bvh.optimize(&config, 10);
+        assert_eq!(report.sah_before, sah);
+        assert_eq!(report.sah_after, bvh.sah_cost(&config));
+        assert!(report.sah_after < report.sah_before);
+        assert!(report.rotations > 0);
+        assert!(report.passes <= 10);
+        assert_eq!(bvh.validate(), Ok(()));
+
+        // Every primitive is still referenced exactly once.
+        let mut indices = bvh.indices().to_vec();
+        indices.sort();
+        assert_eq!(indices, (0..aabbs.len()).collect::<Vec<_>>());
+
+        for ray in random_rays(200, 25) {
+            let expected = aabbs
+                .iter()
+                .filter_map(|aabb| aabb.intersect_ray(&ray, 0., f32::INFINITY))
+                .map(|t| t.0)
+                .min_by(|a, b| a.total_cmp(b));
+            let hit = bvh
+                .intersect_closest(&ray, 0., f32::INFINITY, |i, ray, tmin, tmax| {
+                    aabbs[i].intersect_ray(ray, tmin, tmax).map(|t| t.0)
+                })
+                .map(|hit| hit.1);
+            assert_eq!(hit, expected);
+        }
+
+        // Further passes never make the tree worse.
+        let report = bvh.optimize(&config, 100);
+        assert!(report.sah_after <= report.sah_before);
+        let report = bvh.optimize(&config, 0);
+        assert_eq!(report.passes, 0);
+        assert_eq!(report.rotations, 0);
+    }
+
+    #[test]
+    fn test_optimize_single_leaf() {
+        let aabbs = [AABB::from([1., 1., 1.]); 3];
+        let mut bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &SWEEP).unwrap();
+        assert_eq!(bvh.nodes().len(), 1);
+        let report = bvh.optimize(&SWEEP, 10);
+        assert_eq!(report.rotations, 0);
+        assert_eq!(report.passes, 1);
+    }
+}
@@ -0,0 +1,341 @@
+use crate::aabb::*;
+use crate::bvh::*;
+use rayon::prelude::*;
+
+///
+/// Subtrees with fewer children are built sequentially by a single task.
+///
+const PARALLEL_SUBTREE_MIN: usize = 1 << 12;
+///
+/// Nodes with at least this many children are binned in parallel.
+/// Below that, sorting the children into the buckets is cheaper than spawning tasks for it.
+///
+const PARALLEL_BINNING_MIN: usize = 1 << 16;
+///
+/// Number of children binned by one task.
+///
+const PARALLEL_BINNING_CHUNK: usize = 1 << 13;
+
+impl<Node: BVHNode + Send> BVH<Node>
+where
+    Node::ExternIndex: Send + Sync,
+{
+    ///
+    /// Builds the same BVH as `build` using the current rayon thread pool.
+    /// Run it inside `ThreadPool::install` to limit the number of threads.
+    ///
+    /// Only the binned strategy is parallelised, the other strategies and single threaded pools
+    /// fall back to `build`.
+    /// The left and right subtrees of every node are built by separate tasks into their own
+    /// node vectors, which are then stitched together by the parent (see `append_subtree`).
+    /// Nodes near the root, which hold most of the children, additionally bin their children in
+    /// parallel.
+    ///
+    pub fn build_par<Item: Into<IndexedAABB<Node::ExternIndex>>, I: Iterator<Item = Item>>(
+        iter: I,
+        config: &BuildConfig,
+    ) -> Result<Self, BuildError> {
+        let bins = match config.strategy {
+            BuildStrategy::Binned { bins } if rayon::current_num_threads() > 1 => bins,
+            _ => return Self::build(iter, config),
+        };
+        let mut children: Vec<IndexedAABB<Node::ExternIndex>> = iter.map(|x| x.into()).collect();
+        let aabb = Self::check_children(&children)?;
+        if bins < 2 {
+            return Err(BuildError::InvalidBinCount { bins });
+        }
+        // Buffer of the same length as the children, which the binning levels scatter from.
+        let mut scratch = children.clone();
+        // The pivot of the right most nodes is the root, which is already at index 0.
+        let nodes = Self::par_buckets_pivot(config, aabb, &mut children, &mut scratch, 0, bins);
+        Ok(Self::from_pivot_tree(nodes, &children))
+    }
+    ///
+    /// Parallel version of `buckets_pivot`.
+    /// Returns the nodes of the subtree with local indices, the root of the subtree being at
+    /// index 0. The miss pointers hold the pivots like in `buckets_pivot`, except for the right
+    /// most nodes of the subtree whose pivot lies outside of it. Their pivot is set to 0 and has
+    /// to be fixed by the caller.
+    /// `scratch` has the length of `children`, its content is overwritten.
+    ///
+    fn par_buckets_pivot(
+        config: &BuildConfig,
+        p_aabb: AABB,
+        children: &mut [IndexedAABB<Node::ExternIndex>],
+        scratch: &mut [IndexedAABB<Node::ExternIndex>],
+        offset: usize,
+        bins: usize,
+    ) -> Vec<Node> {
+        if children.len() < PARALLEL_SUBTREE_MIN {
+            let mut nodes = Vec::new();
+            let mut scratch = BinScratch::new(bins);
+            Self::buckets_pivot(
+                &mut nodes,
+                config,
+                p_aabb,
+                children,
+                offset,
+                &mut scratch,
+                0,
+            );
+            return nodes;
+        }
+
+        let centoid_aabb = children
+            .par_iter()
+            .map(|c| AABB::from(c.aabb.centroid()))
+            .reduce(AABB::empty, AABB::grow);
+
+        let (axis, split_axis_size) = centoid_aabb.largest_axis_with_size();
+        let axis: usize = axis.into();
+
+        let (children_split, l_abb, r_abb) = if split_axis_size > 0. {
+            if children.len() >= PARALLEL_BINNING_MIN {
+                Self::par_buckets_split(
+                    config,
+                    p_aabb,
+                    children,
+                    scratch,
+                    centoid_aabb,
+                    bins,
+                )
+            } else {
+                Self::buckets_split(
+                    config,
+                    p_aabb,
+                    children,
+                    &mut BinScratch::new(bins),
+                    centoid_aabb,
+                    axis,
+                    split_axis_size,
+                )
+            }
+        } else {
+            Self::half_split(children)
+        };
+
+        if config.terminate(
+            p_aabb.surface_area(),
+            l_abb.surface_area(),
+            children_split,
+            r_abb.surface_area(),
+            children.len() - children_split,
+        ) {
+            return vec![Node::new_leaf(p_aabb, offset..(offset + children.len()), 0)];
+        }
+
+        let (l_children, r_children) = children.split_at_mut(children_split);
+        let (l_scratch, r_scratch) = scratch.split_at_mut(children_split);
+        let (l_nodes, r_nodes) = rayon::join(
+            || Self::par_buckets_pivot(config, l_abb, l_children, l_scratch, offset, bins),
+            || {
+                let offset = offset + children_split;
+                Self::par_buckets_pivot(config, r_abb, r_children, r_scratch, offset, bins)
+            },
+        );
+
+        let mut nodes = Vec::with_capacity(1 + l_nodes.len() + r_nodes.len());
+        nodes.push(Node::new_node(p_aabb, 1 + l_nodes.len(), 0));
+        // The pivot of the left subtree is this node, the pivot of the right subtree is the
+        // pivot of this node, which is fixed by our caller.
+        Self::append_subtree(&mut nodes, l_nodes, 0);
+        Self::append_subtree(&mut nodes, r_nodes, 0);
+        nodes
+    }
+    ///
+    /// Appends the nodes of a subtree returned by `par_buckets_pivot`, moving its local indices
+    /// to the end of `dst` and setting the pivot of its right most nodes.
+    ///
+    fn append_subtree(dst: &mut Vec<Node>, subtree: Vec<Node>, pivot: usize) {
+        let base = dst.len();
+        dst.extend(subtree.into_iter().map(|mut node| {
+            // The right "pointer" of leaves is part of their range.
+            if node.is_node() {
+                node.set_right(node.right() + base);
+            }
+            node.set_miss(node.miss() + base);
+            node
+        }));
+
+        let mut i = base;
+        loop {
+            dst[i].set_miss(pivot);
+            if dst[i].is_leaf() {
+                break;
+            }
+            i = dst[i].right();
+        }
+    }
+    ///
+    /// Parallel version of `buckets_split`.
+    /// Every task bins a chunk of the children, then the chunks are scattered into the children
+    /// slice ordered by bucket and chunk. This leaves the children in the same order as
+    /// `buckets_split` does. The children are copied to `scratch` to be scattered from, which
+    /// avoids allocating a copy on every level.
+    /// The children are split along the largest axis of `centoid_aabb`, which has to have a size
+    /// greater than 0.
+    ///
+    fn par_buckets_split(
+        config: &BuildConfig,
+        p_aabb: AABB,
+        children: &mut [IndexedAABB<Node::ExternIndex>],
+        scratch: &mut [IndexedAABB<Node::ExternIndex>],
+        centoid_aabb: AABB,
+        n_bins: usize,
+    ) -> (usize, AABB, AABB) {
+        let (axis, split_axis_size) = centoid_aabb.largest_axis_with_size();
+        let axis: usize = axis.into();
+        let bucket_index = |child: &IndexedAABB<Node::ExternIndex>| {
+            Self::bucket_index(child, centoid_aabb, axis, split_axis_size, n_bins)
+        };
+
+        // Count the children and grow the aabbs of every bucket per chunk.
+        let chunk_buckets: Vec<(Vec<usize>, Vec<AABB>)> = children
+            .par_chunks(PARALLEL_BINNING_CHUNK)
+            .map(|chunk| {
+                let mut counts = vec![0; n_bins];
+                let mut aabbs = vec![AABB::empty(); n_bins];
+                for child in chunk {
+                    let n = bucket_index(child);
+                    counts[n] += 1;
+                    aabbs[n] = aabbs[n].grow(child.aabb);
+                }
+                (counts, aabbs)
+            })
+            .collect();
+
+        let mut bucket_counts = vec![0; n_bins];
+        let mut bucket_aabbs = vec![AABB::empty(); n_bins];
+        for (counts, aabbs) in chunk_buckets.iter() {
+            for i in 0..n_bins {
+                bucket_counts[i] += counts[i];
+                bucket_aabbs[i] = bucket_aabbs[i].grow(aabbs[i]);
+            }
+        }
+
+        let mut l_bucket_aabb_acc = vec![AABB::empty(); n_bins];
+        let mut r_bucket_aabb_acc = vec![AABB::empty(); n_bins];
+        let bucket_split = Self::best_bucket_split(
+            config,
+            p_aabb,
+            children.len(),
+            &bucket_aabbs,
+            |i| bucket_counts[i],
+            &mut l_bucket_aabb_acc,
+            &mut r_bucket_aabb_acc,
+        );
+        let children_split = bucket_counts[..=bucket_split].iter().sum();
+
+        // Cut the children slice into disjoint regions for every chunk and bucket, so the chunks
+        // can be written back in parallel.
+        scratch
+            .par_chunks_mut(PARALLEL_BINNING_CHUNK)
+            .zip(children.par_chunks(PARALLEL_BINNING_CHUNK))
+            .for_each(|(dst, src)| dst.copy_from_slice(src));
+        let mut regions: Vec<Vec<&mut [IndexedAABB<Node::ExternIndex>]>> = chunk_buckets
+            .iter()
+            .map(|_| Vec::with_capacity(n_bins))
+            .collect();
+        let mut rest = children;
+        for i in 0..n_bins {
+            for (chunk_regions, (counts, _)) in regions.iter_mut().zip(chunk_buckets.iter()) {
+                let (region, tail) = std::mem::take(&mut rest).split_at_mut(counts[i]);
+                chunk_regions.push(region);
+                rest = tail;
+            }
+        }
+
+        scratch
+            .par_chunks(PARALLEL_BINNING_CHUNK)
+            .zip(regions.par_iter_mut())
+            .for_each(|(chunk, chunk_regions)| {
+                let mut filled = vec![0; n_bins];
+                for child in chunk {
+                    let n = bucket_index(child);
+                    chunk_regions[n][filled[n]] = *child;
+                    filled[n] += 1;
+                }
+            });
+
+        (
+            children_split,
+            l_bucket_aabb_acc[bucket_split],
+            r_bucket_aabb_acc[bucket_split],
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::glsl_bvh::*;
+    use crate::parallel::*;
+
+    /// Runs the closure on a pool with multiple threads, so the parallel path is taken even on
+    /// single core machines.
+    fn with_pool<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap()
+            .install(f)
+    }
+
+    fn assert_same_tree(a: &GlslBVH, b: &GlslBVH) {
+        assert_eq!(
+            bytemuck::cast_slice::<GlslBVHNode, u8>(a.nodes()),
+            bytemuck::cast_slice::<GlslBVHNode, u8>(b.nodes())
+        );
+        assert_eq!(a.indices(), b.indices());
+    }
+
+    #[test]
+    fn test_build_par() {
+        // Large enough to bin the top levels in parallel.
+        let aabbs = random_aabbs(100_000, 15);
+        for config in [BINNED_8, BINNED_16] {
+            let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &config).unwrap();
+            let bvh_par = with_pool(|| {
+                GlslBVH::build_par(aabbs.iter().copied().enumerate(), &config).unwrap()
+            });
+            assert_eq!(bvh_par.validate(), Ok(()));
+            assert_same_tree(&bvh, &bvh_par);
+        }
+
+        // Identical centroids are split in half.
+        let aabbs = vec![AABB::from([1., 1., 1.]); 10_000];
+        let config = BuildConfig {
+            max_leaf_size: 1,
+            ..BINNED_8
+        };
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &config).unwrap();
+        let bvh_par =
+            with_pool(|| GlslBVH::build_par(aabbs.iter().copied().enumerate(), &config).unwrap());
+        assert_same_tree(&bvh, &bvh_par);
+
+        // Other strategies are built sequentially.
+        let aabbs = random_aabbs(1000, 16);
+        let config = SWEEP.with(BuildStrategy::Morton);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &config).unwrap();
+        let bvh_par =
+            with_pool(|| GlslBVH::build_par(aabbs.iter().copied().enumerate(), &config).unwrap());
+        assert_same_tree(&bvh, &bvh_par);
+    }
+
+    #[test]
+    fn test_build_par_errors() {
+        assert_eq!(
+            GlslBVH::build_par(std::iter::empty::<IndexedAABB<usize>>(), &BINNED_8).unwrap_err(),
+            BuildError::EmptyInput
+        );
+        let aabbs = random_aabbs(10, 17);
+        assert_eq!(
+            GlslBVH::build_par(
+                aabbs.iter().copied().enumerate(),
+                &SWEEP.with(BuildStrategy::Binned { bins: 1 })
+            )
+            .unwrap_err(),
+            BuildError::InvalidBinCount { bins: 1 }
+        );
+    }
+}
@@ -0,0 +1,537 @@
+use crate::aabb::*;
+use crate::bvh::*;
+use crate::triangle::*;
+
+///
+/// Spatial splits are only tried if the children of the best object split overlap by more than
+/// this fraction of the surface area of the root.
+///
+const SPATIAL_SPLIT_ALPHA: f32 = 1e-5;
+
+///
+/// State of a spatial split build shared by all nodes.
+///
+struct SpatialScratch<'a, Index, T> {
+    triangles: &'a T,
+    config: &'a BuildConfig,
+    /// Minimal overlap of the object split children for which spatial splits are tried.
+    min_overlap: f32,
+    /// Number of references that may still be duplicated.
+    budget: usize,
+    object: BinScratch<Index>,
+    bin_aabbs: Vec<AABB>,
+    entries: Vec<usize>,
+    exits: Vec<usize>,
+    r_bin_aabb_acc: Vec<AABB>,
+    /// The references of the leaves in the order of the leaves.
+    references: Vec<IndexedAABB<Index>>,
+}
+
+/// The references on the left and right side of a spatial split.
+type SplitReferences<Index> = (Vec<IndexedAABB<Index>>, Vec<IndexedAABB<Index>>);
+
+///
+/// Splits the AABB of a reference to the triangle at the plane along the axis.
+/// The returned AABBs bound the parts of the triangle on either side of the plane and lie
+/// within the original AABB.
+///
+pub fn split_reference(tri: [[f32; 3]; 3], aabb: AABB, axis: usize, plane: f32) -> (AABB, AABB) {
+    let mut l_aabb = AABB::empty();
+    let mut r_aabb = AABB::empty();
+    for i in 0..3 {
+        let v0 = tri[i];
+        let v1 = tri[(i + 1) % 3];
+        if v0[axis] <= plane {
+            l_aabb = l_aabb.grow(v0.into());
+        }
+        if v0[axis] >= plane {
+            r_aabb = r_aabb.grow(v0.into());
+        }
+        // Add the point where the edge crosses the plane to both sides.
+        if (v0[axis] < plane && v1[axis] > plane) || (v0[axis] > plane && v1[axis] < plane) {
+            let t = (plane as f64 - v0[axis] as f64) / (v1[axis] as f64 - v0[axis] as f64);
+            let mut p = [0.; 3];
+            for j in 0..3 {
+                p[j] = (v0[j] as f64 + (v1[j] as f64 - v0[j] as f64) * t) as f32;
+            }
+            p[axis] = plane;
+            l_aabb = l_aabb.grow(p.into());
+            r_aabb = r_aabb.grow(p.into());
+        }
+    }
+    (l_aabb.intersection(aabb), r_aabb.intersection(aabb))
+}
+
+impl<Node: BVHNode> BVH<Node> {
+    ///
+    /// Builds the BVH over the triangles with the given extern indices.
+    ///
+    /// With the spatial strategy this creates an SBVH as described by Stich et al.
+    /// https://www.nvidia.in/docs/IO/77714/sbvh.pdf
+    /// Besides the binned object split every node evaluates spatial splits, which cut the
+    /// triangles crossing the split plane into two references with tighter AABBs. Triangles can
+    /// therefore be referenced by multiple leaves, `indices` may contain duplicates.
+    /// The number of references is limited to twice the number of triangles.
+    ///
+    /// All other strategies build the same tree as `build` over the AABBs of the triangles.
+    ///
+    pub fn build_triangles<
+        T: Triangles<Node::ExternIndex>,
+        I: Iterator<Item = Node::ExternIndex>,
+    >(
+        triangles: &T,
+        iter: I,
+        config: &BuildConfig,
+    ) -> Result<Self, BuildError> {
+        let children = iter.map(|index| IndexedAABB {
+            index,
+            aabb: triangles.triangle(index).into(),
+        });
+        let bins = match config.strategy {
+            BuildStrategy::Spatial { bins } => bins,
+            _ => return Self::build(children, config),
+        };
+        let children: Vec<IndexedAABB<Node::ExternIndex>> = children.collect();
+        let aabb = Self::check_children(&children)?;
+        if bins < 2 {
+            return Err(BuildError::InvalidBinCount { bins });
+        }
+
+        let mut scratch = SpatialScratch {
+            triangles,
+            config,
+            min_overlap: aabb.surface_area() * SPATIAL_SPLIT_ALPHA,
+            budget: children.len().min(Node::MAX_INDEX / 2 - children.len()),
+            object: BinScratch::new(bins),
+            bin_aabbs: vec![AABB::empty(); bins],
+            entries: vec![0; bins],
+            exits: vec![0; bins],
+            r_bin_aabb_acc: vec![AABB::empty(); bins],
+            references: Vec::with_capacity(children.len()),
+        };
+        let mut nodes = Vec::new();
+        Self::spatial_pivot(&mut nodes, &mut scratch, aabb, children, 0);
+        Ok(Self::from_pivot_tree(nodes, &scratch.references))
+    }
+    ///
+    /// Recursive part of the SBVH builder, see `buckets_pivot` for the meaning of `pivot`.
+    /// Unlike the other builders the references are moved into the leaves as they are created.
+    ///
+    fn spatial_pivot<T: Triangles<Node::ExternIndex>>(
+        dst: &mut Vec<Node>,
+        scratch: &mut SpatialScratch<Node::ExternIndex, T>,
+        p_aabb: AABB,
+        mut references: Vec<IndexedAABB<Node::ExternIndex>>,
+        pivot: usize,
+    ) -> usize {
+        let n = references.len();
+        if n == 1 {
+            return Self::spatial_leaf(dst, scratch, p_aabb, references, pivot);
+        }
+
+        // Find the best object split, as in the binned builder.
+        let (children_split, o_l_aabb, o_r_aabb) = if n == 2 {
+            (1, references[0].aabb, references[1].aabb)
+        } else {
+            let centoid_aabb: AABB = references
+                .iter()
+                .map(|c| c.aabb.centroid().into())
+                .fold(AABB::empty(), AABB::grow);
+            let (axis, split_axis_size) = centoid_aabb.largest_axis_with_size();
+            if split_axis_size > 0. {
+                Self::buckets_split(
+                    scratch.config,
+                    p_aabb,
+                    &mut references,
+                    &mut scratch.object,
+                    centoid_aabb,
+                    axis.into(),
+                    split_axis_size,
+                )
+            } else {
+                Self::half_split(&references)
+            }
+        };
+        let p_sa = p_aabb.surface_area();
+        let object_cost = scratch.config.split_cost(
+            p_sa,
+            o_l_aabb.surface_area(),
+            children_split,
+            o_r_aabb.surface_area(),
+            n - children_split,
+        );
+
+        // Try a spatial split if the children of the object split overlap.
+        let mut spatial = None;
+        if scratch.budget > 0
+            && o_l_aabb.intersection(o_r_aabb).surface_area() > scratch.min_overlap
+        {
+            if let Some((axis, plane, cost)) = Self::spatial_split(scratch, p_aabb, &references) {
+                if cost < object_cost {
+                    let (l_refs, r_refs) =
+                        Self::split_references(scratch, &references, axis, plane);
+                    let duplicates = (l_refs.len() + r_refs.len()).saturating_sub(n);
+                    // Both sides can hold all references if every triangle crosses the plane.
+                    // This still terminates since every such split uses up some of the budget.
+                    if !l_refs.is_empty() && !r_refs.is_empty() && duplicates <= scratch.budget {
+                        spatial = Some((l_refs, r_refs));
+                    }
+                }
+            }
+        }
+
+        let (l_refs, r_refs) = match spatial {
+            Some((l_refs, r_refs)) => {
+                scratch.budget -= (l_refs.len() + r_refs.len()).saturating_sub(n);
+                (l_refs, r_refs)
+            }
+            None => {
+                let r_refs = references.split_off(children_split);
+                (references, r_refs)
+            }
+        };
+        let l_aabb = l_refs
+            .iter()
+            .map(|c| c.aabb)
+            .fold(AABB::empty(), AABB::grow);
+        let r_aabb = r_refs
+            .iter()
+            .map(|c| c.aabb)
+            .fold(AABB::empty(), AABB::grow);
+
+        if scratch.config.terminate(
+            p_sa,
+            l_aabb.surface_area(),
+            l_refs.len(),
+            r_aabb.surface_area(),
+            r_refs.len(),
+        ) {
+            let mut references = l_refs;
+            references.extend(r_refs);
+            return Self::spatial_leaf(dst, scratch, p_aabb, references, pivot);
+        }
+
+        let node_i = dst.len();
+        dst.push(Node::new_node(p_aabb, 0, pivot));
+        Self::spatial_pivot(dst, scratch, l_aabb, l_refs, node_i);
+        let r_node_i = Self::spatial_pivot(dst, scratch, r_aabb, r_refs, pivot);
+        dst[node_i].set_right(r_node_i);
+        node_i
+    }
+    fn spatial_leaf<T>(
+        dst: &mut Vec<Node>,
+        scratch: &mut SpatialScratch<Node::ExternIndex, T>,
+        p_aabb: AABB,
+        references: Vec<IndexedAABB<Node::ExternIndex>>,
+        pivot: usize,
+    ) -> usize {
+        let start = scratch.references.len();
+        scratch.references.extend(references);
+        dst.push(Node::new_leaf(
+            p_aabb,
+            start..scratch.references.len(),
+            pivot,
+        ));
+        dst.len() - 1
+    }
+    ///
+    /// Bins the references spatially along the largest axis of the parent, clipping them
+    /// against the bin boundaries.
+    ///
+    /// Returns the axis and position of the plane with the lowest SAH cost and that cost.
+    ///
+    fn spatial_split<T: Triangles<Node::ExternIndex>>(
+        scratch: &mut SpatialScratch<Node::ExternIndex, T>,
+        p_aabb: AABB,
+        references: &[IndexedAABB<Node::ExternIndex>],
+    ) -> Option<(usize, f32, f32)> {
+        let (axis, axis_size) = p_aabb.largest_axis_with_size();
+        let axis: usize = axis.into();
+        if axis_size.is_nan() || axis_size <= 0. {
+            return None;
+        }
+        let n_bins = scratch.bin_aabbs.len();
+        let bin_size = axis_size / n_bins as f32;
+        let plane = |i: usize| p_aabb.min[axis] + bin_size * (i + 1) as f32;
+        // Bins a bound of a reference against the same planes `split_references` compares with.
+        // A minimum on a plane lies right of it and a maximum on a plane left of it.
+        let bin = |x: f32, max: bool| {
+            let left_of = |i: usize| if max { x <= plane(i) } else { x < plane(i) };
+            let mut i = (((x - p_aabb.min[axis]) / axis_size * n_bins as f32) as usize)
+                .min(n_bins - 1);
+            while i > 0 && left_of(i - 1) {
+                i -= 1;
+            }
+            while i < n_bins - 1 && !left_of(i) {
+                i += 1;
+            }
+            i
+        };
+
+        scratch.bin_aabbs.fill(AABB::empty());
+        scratch.entries.fill(0);
+        scratch.exits.fill(0);
+        for reference in references {
+            let last = bin(reference.aabb.max[axis], true);
+            // References flat on a plane lie left of it.
+            let first = bin(reference.aabb.min[axis], false).min(last);
+            scratch.entries[first] += 1;
+            scratch.exits[last] += 1;
+
+            // Clip the reference against every bin boundary it crosses.
+            let tri = scratch.triangles.triangle(reference.index);
+            let mut aabb = reference.aabb;
+            for i in first..last {
+                let (l_aabb, r_aabb) = split_reference(tri, aabb, axis, plane(i));
+                scratch.bin_aabbs[i] = scratch.bin_aabbs[i].grow(l_aabb);
+                aabb = r_aabb;
+            }
+            scratch.bin_aabbs[last] = scratch.bin_aabbs[last].grow(aabb);
+        }
+
+        let mut r_aabb = AABB::empty();
+        for i in (1..n_bins).rev() {
+            r_aabb = r_aabb.grow(scratch.bin_aabbs[i]);
+            scratch.r_bin_aabb_acc[i - 1] = r_aabb;
+        }
+
+        // Sweep the planes between the bins. References entering a bin left of the plane are
+        // on the left side, references exiting a bin right of it on the right side.
+        let p_sa = p_aabb.surface_area();
+        let mut best = None;
+        let mut min_cost = f32::INFINITY;
+        let mut l_aabb = AABB::empty();
+        let mut l_n = 0;
+        let mut r_n = references.len();
+        for i in 0..(n_bins - 1) {
+            l_aabb = l_aabb.grow(scratch.bin_aabbs[i]);
+            l_n += scratch.entries[i];
+            r_n -= scratch.exits[i];
+            if l_n == 0 || r_n == 0 {
+                continue;
+            }
+            let cost = scratch.config.split_cost(
+                p_sa,
+                l_aabb.surface_area(),
+                l_n,
+                scratch.r_bin_aabb_acc[i].surface_area(),
+                r_n,
+            );
+            if cost < min_cost {
+                min_cost = cost;
+                best = Some(plane(i));
+            }
+        }
+        best.map(|plane| (axis, plane, min_cost))
+    }
+    ///
+    /// Distributes the references to the sides of the plane, splitting the ones crossing it.
+    ///
+    fn split_references<T: Triangles<Node::ExternIndex>>(
+        scratch: &SpatialScratch<Node::ExternIndex, T>,
+        references: &[IndexedAABB<Node::ExternIndex>],
+        axis: usize,
+        plane: f32,
+    ) -> SplitReferences<Node::ExternIndex> {
+        let mut l_refs = Vec::new();
+        let mut r_refs = Vec::new();
+        for reference in references {
+            if reference.aabb.max[axis] <= plane {
+                l_refs.push(*reference);
+            } else if reference.aabb.min[axis] >= plane {
+                r_refs.push(*reference);
+            } else {
+                let tri = scratch.triangles.triangle(reference.index);
+                let (l_aabb, r_aabb) = split_reference(tri, reference.aabb, axis, plane);
+                // Triangles only touching the plane have an empty part on one side.
+                if !l_aabb.is_empty() {
+                    l_refs.push(IndexedAABB {
+                        index: reference.index,
+                        aabb: l_aabb,
+                    });
+                }
+                if !r_aabb.is_empty() {
+                    r_refs.push(IndexedAABB {
+                        index: reference.index,
+                        aabb: r_aabb,
+                    });
+                }
+            }
+        }
+        (l_refs, r_refs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::glsl_bvh::*;
+    use crate::sbvh::*;
+
+    struct Soup(Vec<[[f32; 3]; 3]>);
+
+    impl Triangles<usize> for Soup {
+        fn triangle(&self, index: usize) -> [[f32; 3]; 3] {
+            self.0[index]
+        }
+    }
+
+    ///
+    /// Long thin triangles along the diagonal of a box, for which all object splits overlap.
+    ///
+    fn diagonal_slivers(n: usize, seed: u64) -> Soup {
+        let mut rng = Lcg(seed);
+        Soup(
+            (0..n)
+                .map(|_| {
+                    let a = rng.next_vec3(1.);
+                    let b = rng.next_vec3(1.);
+                    let c = [a[0] + 0.01, a[1], a[2] + 0.01];
+                    [a, [10. - b[0], 10. - b[1], 10. - b[2]], c]
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_split_reference() {
+        let tri = [[0., 0., 0.], [2., 0., 0.], [0., 2., 0.]];
+        let aabb = AABB::from(tri);
+        let (l_aabb, r_aabb) = split_reference(tri, aabb, 0, 1.);
+        assert_eq!(l_aabb.min, [0., 0., 0.]);
+        assert_eq!(l_aabb.max, [1., 2., 0.]);
+        assert_eq!(r_aabb.min, [1., 0., 0.]);
+        assert_eq!(r_aabb.max, [2., 1., 0.]);
+
+        // The result is clipped against the AABB of the reference.
+        let clipped = AABB {
+            min: [0., 0., 0.],
+            max: [2., 0.5, 0.],
+        };
+        let (l_aabb, _) = split_reference(tri, clipped, 0, 1.);
+        assert_eq!(l_aabb.max, [1., 0.5, 0.]);
+
+        // Triangles touching the plane have an empty part on one side.
+        let (l_aabb, r_aabb) = split_reference(tri, aabb, 0, 0.);
+        assert!(!l_aabb.is_empty());
+        assert_eq!(l_aabb.max[0], 0.);
+        assert!(!r_aabb.is_empty());
+        let (_, r_aabb) = split_reference(tri, aabb, 0, 3.);
+        assert!(r_aabb.is_empty());
+    }
+
+    #[test]
+    fn test_spatial_split_planes() {
+        // Triangles with vertices on the planes at 1, 2 and 3 between the 4 bins.
+        let soup = Soup(vec![
+            [[0., 0., 0.], [2., 0., 0.], [0., 1., 0.]],
+            [[2., 0., 0.], [4., 0., 0.], [4., 1., 0.]],
+            [[2., 0., 0.], [2., 1., 0.], [2., 0., 1.]],
+            [[1., 0., 0.], [3., 1., 0.], [1., 1., 0.]],
+            [[0.5, 0., 0.], [3., 0., 1.], [1., 1., 1.]],
+        ]);
+        let references: Vec<IndexedAABB<usize>> = soup
+            .0
+            .iter()
+            .enumerate()
+            .map(|(index, &tri)| IndexedAABB {
+                index,
+                aabb: tri.into(),
+            })
+            .collect();
+        let p_aabb = references
+            .iter()
+            .map(|c| c.aabb)
+            .fold(AABB::empty(), AABB::grow);
+        let config = SWEEP.with(BuildStrategy::Spatial { bins: 4 });
+        let mut scratch = SpatialScratch {
+            triangles: &soup,
+            config: &config,
+            min_overlap: 0.,
+            budget: references.len(),
+            object: BinScratch::new(4),
+            bin_aabbs: vec![AABB::empty(); 4],
+            entries: vec![0; 4],
+            exits: vec![0; 4],
+            r_bin_aabb_acc: vec![AABB::empty(); 4],
+            references: Vec::new(),
+        };
+
+        let (axis, best, _) = GlslBVH::spatial_split(&mut scratch, p_aabb, &references).unwrap();
+        assert_eq!(axis, 0);
+        assert!([1., 2., 3.].contains(&best));
+        // The binned counts agree with the references distributed on every plane.
+        for i in 0..3 {
+            let plane = (i + 1) as f32;
+            let (l_refs, r_refs) = GlslBVH::split_references(&scratch, &references, 0, plane);
+            let l_n: usize = scratch.entries[..=i].iter().sum();
+            let r_n = references.len() - scratch.exits[..=i].iter().sum::<usize>();
+            assert_eq!((l_n, r_n), (l_refs.len(), r_refs.len()), "plane {plane}");
+        }
+    }
+
+    #[test]
+    fn test_build_spatial() {
+        let soup = diagonal_slivers(500, 18);
+        let spatial = SWEEP.with(BuildStrategy::Spatial { bins: 16 });
+
+        let bvh = GlslBVH::build_triangles(&soup, 0..soup.0.len(), &spatial).unwrap();
+        assert_eq!(bvh.validate(), Ok(()));
+        // References are duplicated but stay within the budget.
+        assert!(bvh.indices().len() > soup.0.len());
+        assert!(bvh.indices().len() <= 2 * soup.0.len());
+
+        let binned = GlslBVH::build_triangles(&soup, 0..soup.0.len(), &BINNED_16).unwrap();
+        assert!(bvh.sah_cost(&spatial) < binned.sah_cost(&BINNED_16));
+
+        let mut rng = Lcg(19);
+        for _ in 0..500 {
+            let origin = [-1., rng.next_f32() * 10., rng.next_f32() * 10.];
+            let target = [11., rng.next_f32() * 10., rng.next_f32() * 10.];
+            let ray = Ray::new(
+                origin,
+                [
+                    target[0] - origin[0],
+                    target[1] - origin[1],
+                    target[2] - origin[2],
+                ],
+            );
+            let expected = soup
+                .0
+                .iter()
+                .filter_map(|tri| intersect_triangle(&ray, *tri, 0., f32::INFINITY))
+                .map(|hit| hit.t)
+                .min_by(|a, b| a.total_cmp(b));
+            let hit = bvh
+                .intersect_closest(&ray, 0., f32::INFINITY, |i, ray, tmin, tmax| {
+                    intersect_triangle(ray, soup.0[i], tmin, tmax)
+                })
+                .map(|hit| hit.1.t);
+            assert_eq!(hit, expected);
+        }
+    }
+
+    #[test]
+    fn test_build_spatial_errors() {
+        let soup = diagonal_slivers(10, 20);
+        let aabbs: Vec<AABB> = soup.0.iter().map(|&tri| tri.into()).collect();
+        let spatial = SWEEP.with(BuildStrategy::Spatial { bins: 16 });
+        assert_eq!(
+            GlslBVH::build(aabbs.iter().copied().enumerate(), &spatial).unwrap_err(),
+            BuildError::MissingTriangles
+        );
+        assert_eq!(
+            GlslBVH::build_triangles(
+                &soup,
+                0..10,
+                &SWEEP.with(BuildStrategy::Spatial { bins: 1 })
+            )
+            .unwrap_err(),
+            BuildError::InvalidBinCount { bins: 1 }
+        );
+        assert_eq!(
+            GlslBVH::build_triangles(&soup, 0..0, &spatial).unwrap_err(),
+            BuildError::EmptyInput
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use crate::camera::*;
+use crate::compact_bvh::*;
+use crate::glsl_bvh::*;
+
+///
+/// Types of the fields of structs shared with the shaders.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderType {
+    F32,
+    U32,
+    Vec4F32,
+}
+
+impl ShaderType {
+    pub fn glsl(&self) -> &'static str {
+        match self {
+            ShaderType::F32 => "float",
+            ShaderType::U32 => "uint",
+            ShaderType::Vec4F32 => "vec4",
+        }
+    }
+
+    pub fn wgsl(&self) -> &'static str {
+        match self {
+            ShaderType::F32 => "f32",
+            ShaderType::U32 => "u32",
+            ShaderType::Vec4F32 => "vec4<f32>",
+        }
+    }
+
+    ///
+    /// Size and alignment in std430, which are the same in the storage address space of WGSL.
+    ///
+    pub fn std430_size_align(&self) -> (usize, usize) {
+        match self {
+            ShaderType::F32 | ShaderType::U32 => (4, 4),
+            ShaderType::Vec4F32 => (16, 16),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShaderField {
+    pub name: &'static str,
+    pub ty: ShaderType,
+    /// Length of the array, None for single values.
+    pub array: Option<usize>,
+    /// Offset of the field in the Rust struct.
+    pub offset: usize,
+}
+
+///
+/// Layout of a Rust struct that is stored in the buffers of the shaders.
+/// The declarations in the shaders are generated from it, so that the layouts on the host and
+/// in the shaders cannot drift apart (see `impl_shader_layout`).
+///
+pub trait ShaderLayout: bytemuck::Pod {
+    /// Name of the struct in the shaders.
+    const NAME: &'static str;
+
+    fn fields() -> Vec<ShaderField>;
+
+    ///
+    /// Offsets of the fields and size of the struct following the std430 rules.
+    /// Arrays of scalars and vec4 have the stride of their element in std430.
+    ///
+    fn std430_layout() -> (Vec<usize>, usize) {
+        let mut offsets = Vec::new();
+        let mut end: usize = 0;
+        let mut struct_align = 1;
+        for field in Self::fields() {
+            let (size, align) = field.ty.std430_size_align();
+            let offset = end.next_multiple_of(align);
+            offsets.push(offset);
+            end = offset + size * field.array.unwrap_or(1);
+            struct_align = struct_align.max(align);
+        }
+        (offsets, end.next_multiple_of(struct_align))
+    }
+
+    ///
+    /// The GLSL declaration of the struct.
+    ///
+    fn glsl_struct() -> String {
+        let mut glsl = format!("struct {}{{\n", Self::NAME);
+        for field in Self::fields() {
+            match field.array {
+                Some(len) => glsl += &format!("    {} {}[{}];\n", field.ty.glsl(), field.name, len),
+                None => glsl += &format!("    {} {};\n", field.ty.glsl(), field.name),
+            }
+        }
+        glsl + "};\n"
+    }
+
+    ///
+    /// The WGSL declaration of the struct.
+    ///
+    fn wgsl_struct() -> String {
+        let mut wgsl = format!("struct {}{{\n", Self::NAME);
+        for field in Self::fields() {
+            match field.array {
+                Some(len) => {
+                    wgsl += &format!("    {}: array<{}, {}>,\n", field.name, field.ty.wgsl(), len)
+                }
+                None => wgsl += &format!("    {}: {},\n", field.name, field.ty.wgsl()),
+            }
+        }
+        wgsl + "}\n"
+    }
+}
+
+///
+/// Implements `ShaderLayout` for a struct by listing the shader types of its fields in order,
+/// either the name of a `ShaderType` or `[ShaderType; len]` for arrays.
+///
+macro_rules! impl_shader_layout {
+    (@ty [$ty:ident; $len:literal]) => {
+        ($crate::shader_layout::ShaderType::$ty, Some($len))
+    };
+    (@ty $ty:ident) => {
+        ($crate::shader_layout::ShaderType::$ty, None)
+    };
+    ($rust:ty, $name:literal, { $($field:ident: $ty:tt),* $(,)? }) => {
+        impl $crate::shader_layout::ShaderLayout for $rust {
+            const NAME: &'static str = $name;
+
+            fn fields() -> Vec<$crate::shader_layout::ShaderField> {
+                vec![$({
+                    let (ty, array) = $crate::shader_layout::impl_shader_layout!(@ty $ty);
+                    $crate::shader_layout::ShaderField {
+                        name: stringify!($field),
+                        ty,
+                        array,
+                        offset: std::mem::offset_of!($rust, $field),
+                    }
+                }),*]
+            }
+        }
+    };
+}
+pub(crate) use impl_shader_layout;
+
+impl_shader_layout!(GlslBVHNode, "BVHNode", {
+    min: Vec4F32,
+    max: Vec4F32,
+    ty: U32,
+    right: U32,
+    miss: U32,
+    count: U32,
+});
+
+impl_shader_layout!(CompactBVHNode, "CompactBVHNode", {
+    min: [F32; 3],
+    extent_xy: U32,
+    extent_z_exp: U32,
+    right: U32,
+    miss: U32,
+    count: U32,
+});
+
+impl_shader_layout!(GlslInstance, "Instance", {
+    world_to_object: [Vec4F32; 3],
+    node_offset: U32,
+    index_offset: U32,
+    mask: U32,
+    id: U32,
+});
+
+impl_shader_layout!(GlslCamera, "Camera", {
+    origin: Vec4F32,
+    corner: Vec4F32,
+    right: Vec4F32,
+    down: Vec4F32,
+});
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::BVHNode;
+    use crate::shader_layout::*;
+    use std::collections::HashMap;
+
+    /// Offsets of the members and size of a struct as laid out by naga.
+    type NagaLayout = (Vec<(String, usize)>, usize);
+
+    fn assert_layout<T: ShaderLayout>() {
+        let (offsets, size) = T::std430_layout();
+        let rust_offsets: Vec<usize> = T::fields().iter().map(|field| field.offset).collect();
+        assert_eq!(offsets, rust_offsets, "field offsets of {}", T::NAME);
+        assert_eq!(size, std::mem::size_of::<T>(), "size of {}", T::NAME);
+
+        // The shaders declare the generated structs.
+        assert!(
+            include_str!("shaders/trace.glsl").contains(&T::glsl_struct()),
+            "trace.glsl does not declare\n{}",
+            T::glsl_struct()
+        );
+        assert!(
+            include_str!("shaders/trace.wgsl").contains(&T::wgsl_struct()),
+            "trace.wgsl does not declare\n{}",
+            T::wgsl_struct()
+        );
+    }
+
+    fn validate_naga(
+        module: &naga::Module,
+        capabilities: naga::valid::Capabilities,
+    ) -> Result<(), naga::WithSpan<naga::valid::ValidationError>> {
+        naga::valid::Validator::new(naga::valid::ValidationFlags::all(), capabilities)
+            .validate(module)
+            .map(|_| ())
+    }
+
+    fn parse_wgsl() -> naga::Module {
+        let source = include_str!("shaders/trace.wgsl");
+        let module = naga::front::wgsl::parse_str(source)
+            .unwrap_or_else(|err| panic!("trace.wgsl:\n{}", err.emit_to_string(source)));
+        // WGSL is traced in single precision and runs without any features.
+        if let Err(err) = validate_naga(&module, naga::valid::Capabilities::empty()) {
+            panic!("trace.wgsl is invalid:\n{}", err.emit_to_string(source));
+        }
+        module
+    }
+
+    fn parse_glsl(trace_tlas: bool) -> naga::Module {
+        let source = include_str!("shaders/trace.glsl");
+        let mut options = naga::front::glsl::Options::from(naga::ShaderStage::Compute);
+        options
+            .defines
+            .insert("COMPUTE_SHADER".to_string(), "1".to_string());
+        if trace_tlas {
+            options
+                .defines
+                .insert("TRACE_TLAS".to_string(), "1".to_string());
+        }
+        // The front-end does not support the precise qualifier, so this parses a variant of
+        // trace.glsl without it and not the source `ComputeShader::from_src_glsl` compiles. The
+        // qualifier does not change the declarations, so the reflected layouts and constants are
+        // those of the shipped shader, but this does not prove that the shipped source compiles.
+        options
+            .defines
+            .insert("PRECISE".to_string(), "".to_string());
+        let module = naga::front::glsl::Frontend::default()
+            .parse(&options, source)
+            .unwrap_or_else(|err| panic!("trace.glsl:\n{}", err.emit_to_string(source)));
+        // The divisions and the fallback of the triangle test use double precision, which is
+        // why `TracePipeline::load` requires `wgpu::Features::SHADER_FLOAT64`.
+        assert!(validate_naga(&module, naga::valid::Capabilities::empty()).is_err());
+        if let Err(err) = validate_naga(&module, naga::valid::Capabilities::FLOAT64) {
+            panic!("trace.glsl is invalid:\n{}", err.emit_to_string(source));
+        }
+        module
+    }
+
+    fn collect_structs(
+        module: &naga::Module,
+        ty: naga::Handle<naga::Type>,
+        structs: &mut HashMap<String, NagaLayout>,
+    ) {
+        match &module.types[ty].inner {
+            naga::TypeInner::Struct { members, span } => {
+                if let Some(name) = &module.types[ty].name {
+                    let offsets = members
+                        .iter()
+                        .map(|member| {
+                            (
+                                member.name.clone().unwrap_or_default(),
+                                member.offset as usize,
+                            )
+                        })
+                        .collect();
+                    structs.insert(name.clone(), (offsets, *span as usize));
+                }
+                for member in members {
+                    collect_structs(module, member.ty, structs);
+                }
+            }
+            naga::TypeInner::Array { base, .. } => collect_structs(module, *base, structs),
+            _ => {}
+        }
+    }
+
+    ///
+    /// Layouts of the named structs in the storage and uniform buffers of the module.
+    /// Only these are reflected, since the GLSL front-end lays out structs that are not used
+    /// in a buffer with the default rules instead of the ones of the buffer.
+    ///
+    fn buffer_structs(module: &naga::Module) -> HashMap<String, NagaLayout> {
+        let mut structs = HashMap::new();
+        for (_, var) in module.global_variables.iter() {
+            if let naga::AddressSpace::Storage { .. } | naga::AddressSpace::Uniform = var.space {
+                collect_structs(module, var.ty, &mut structs);
+            }
+        }
+        structs
+    }
+
+    fn assert_naga_layout<T: ShaderLayout>(structs: &HashMap<String, NagaLayout>, shader: &str) {
+        let (offsets, size) = structs
+            .get(T::NAME)
+            .unwrap_or_else(|| panic!("{} is not used in a buffer of {}", T::NAME, shader));
+        let rust_offsets: Vec<(String, usize)> = T::fields()
+            .iter()
+            .map(|field| (field.name.to_string(), field.offset))
+            .collect();
+        assert_eq!(
+            offsets,
+            &rust_offsets,
+            "fields of {} in {}",
+            T::NAME,
+            shader
+        );
+        assert_eq!(
+            *size,
+            std::mem::size_of::<T>(),
+            "size of {} in {}",
+            T::NAME,
+            shader
+        );
+    }
+
+    #[test]
+    fn test_naga_layout() {
+        let modules = [
+            (parse_wgsl(), "trace.wgsl"),
+            (parse_glsl(false), "trace.glsl"),
+            (parse_glsl(true), "trace.glsl with TRACE_TLAS"),
+        ];
+        for (module, shader) in &modules {
+            let structs = buffer_structs(module);
+            assert_naga_layout::<GlslBVHNode>(&structs, shader);
+            assert_naga_layout::<crate::Vert>(&structs, shader);
+            // Only bound by some of the shaders.
+            if structs.contains_key(CompactBVHNode::NAME) {
+                assert_naga_layout::<CompactBVHNode>(&structs, shader);
+            }
+            if structs.contains_key(GlslInstance::NAME) {
+                assert_naga_layout::<GlslInstance>(&structs, shader);
+            }
+            if structs.contains_key(GlslCamera::NAME) {
+                assert_naga_layout::<GlslCamera>(&structs, shader);
+            }
+        }
+        // The TLAS traversal binds the instances.
+        assert!(buffer_structs(&modules[2].0).contains_key(GlslInstance::NAME));
+    }
+
+    fn constant(module: &naga::Module, name: &str) -> naga::Literal {
+        let (_, constant) = module
+            .constants
+            .iter()
+            .find(|(_, constant)| constant.name.as_deref() == Some(name))
+            .unwrap_or_else(|| panic!("{} is not declared", name));
+        match module.global_expressions[constant.init] {
+            naga::Expression::Literal(literal) => literal,
+            ref init => panic!("{} is initialized with {:?}", name, init),
+        }
+    }
+
+    #[test]
+    fn test_shader_constants() {
+        // The traversals of the shaders have to agree with the one on the CPU.
+        for module in [parse_wgsl(), parse_glsl(false)] {
+            assert_eq!(
+                constant(&module, "MISS_SENTINEL"),
+                naga::Literal::U32(GlslBVHNode::MISS_SENTINEL as u32)
+            );
+            assert_eq!(
+                constant(&module, "TY_LEAF"),
+                naga::Literal::U32(GlslBVHNode::TY_LEAF)
+            );
+            assert_eq!(constant(&module, "NO_HIT"), naga::Literal::U32(NO_HIT));
+            assert_eq!(
+                constant(&module, "SLAB_TFAR_SCALE"),
+                naga::Literal::F32(crate::aabb::AABB::SLAB_TFAR_SCALE)
+            );
+        }
+    }
+
+    #[test]
+    fn test_shader_layout() {
+        assert_layout::<GlslBVHNode>();
+        assert_layout::<CompactBVHNode>();
+        assert_layout::<GlslInstance>();
+        assert_layout::<GlslCamera>();
+        assert_layout::<crate::Vert>();
+
+        assert_eq!(
+            GlslBVHNode::glsl_struct(),
+            "struct BVHNode{\n    vec4 min;\n    vec4 max;\n    uint ty;\n    uint right;\n    \
+             uint miss;\n    uint count;\n};\n"
+        );
+        assert_eq!(
+            CompactBVHNode::wgsl_struct(),
+            "struct CompactBVHNode{\n    min: array<f32, 3>,\n    extent_xy: u32,\n    \
+             extent_z_exp: u32,\n    right: u32,\n    miss: u32,\n    count: u32,\n}\n"
+        );
+    }
+}
@@ -2,33 +2,417 @@
 //#extension GL_EXT_nonunifomr_qualifier: require
 #if COMPUTE_SHADER
 
+// The hits have to be bit identical to the ones of the CPU reference (see trace_hits), so
+// multiplications and additions must not be contracted into fmas. naga does not know the
+// qualifier, its tests define PRECISE as empty.
+#ifndef PRECISE
+#define PRECISE precise
+#endif
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+// Miss pointer of the nodes after which the ray leaves the tree (see BVHNode::MISS_SENTINEL).
+const uint MISS_SENTINEL = 0xffffffffu;
+// Type of the leaves (see GlslNode::TY_LEAF).
+const uint TY_LEAF = 1u;
+// Written to hits for pixels whose ray does not hit the mesh (see NO_HIT).
+const uint NO_HIT = 0xffffffffu;
+// 1 + 3 * FLT_EPSILON (see AABB::SLAB_TFAR_SCALE).
+const float SLAB_TFAR_SCALE = 1.00000036;
 
 struct Vert{
     vec4 pos;
     vec4 color;
 };
+// The structs are generated from the Rust types (see ShaderLayout) and have to be kept in sync.
+
+// For leaves right is the index of the first primitive in bvh_indices and count the number of
+// primitives, count is 0 for inner nodes.
 struct BVHNode{
     vec4 min;
     vec4 max;
     uint ty;
     uint right;
     uint miss;
+    uint count;
+};
+// Node with quantised bounds (see CompactBVHNode), max = min + extent * scale.
+// extent_xy holds the extent along x in the low and along y in the high 16 bits, extent_z_exp
+// the extent along z in the low 16 bits and the biased exponent of the scale in bits 16..24.
+struct CompactBVHNode{
+    float min[3];
+    uint extent_xy;
+    uint extent_z_exp;
+    uint right;
+    uint miss;
+    uint count;
+};
+// Instance of a BLAS in a TLAS. The nodes and indices of all BLASes are concatenated, the
+// pointers inside of a BLAS are relative to node_offset and index_offset, except for
+// MISS_SENTINEL.
+struct Instance{
+    vec4 world_to_object[3];
+    uint node_offset;
+    uint index_offset;
+    uint mask;
+    uint id;
+};
+// Pinhole camera (see GlslCamera), the ray through the center of pixel (x, y) has the direction
+// corner + (x + 0.5) * right + (y + 0.5) * down.
+struct Camera{
+    vec4 origin;
+    vec4 corner;
+    vec4 right;
+    vec4 down;
 };
 
-layout(set = 0, binding = 0) buffer BVH{
+// With TRACE_TLAS defined the camera rays are traced through a TLAS (see GlslTLASBuffers), bvh
+// and bvh_indices then hold the concatenated BLASes. The verts and indices of all meshes are
+// concatenated as well and the extern indices of the BLASes point into indices.
+layout(std430, set = 0, binding = 0) buffer BVH{
     BVHNode nodes[];
 }bvh;
-layout(set = 0, binding = 1) buffer Verts{
+layout(std430, set = 0, binding = 1) buffer Verts{
     Vert verts[];
 };
-layout(set = 0, binding = 2) buffer Indices{
+layout(std430, set = 0, binding = 2) buffer Indices{
     uint indices[];
 };
+layout(std430, set = 0, binding = 3) buffer BVHIndices{
+    uint bvh_indices[];
+};
+#if TRACE_TLAS
+layout(std430, set = 0, binding = 4) buffer TLAS{
+    BVHNode nodes[];
+}tlas;
+// Maps the indices of the TLAS leaves to instances.
+layout(std430, set = 0, binding = 5) buffer TLASIndices{
+    uint tlas_indices[];
+};
+layout(std430, set = 0, binding = 6) buffer Instances{
+    Instance instances[];
+};
+#endif
 
 layout(set = 1, binding = 0, rgba8) writeonly uniform image2D dst;
+// Extern index of the closest triangle hit through every pixel in row major order or NO_HIT.
+layout(std430, set = 1, binding = 1) buffer Hits{
+    uint hits[];
+};
+
+layout(std140, set = 2, binding = 0) uniform CameraBlock{
+    Camera camera;
+};
+
+struct Ray{
+    vec3 origin;
+    vec3 dir;
+    vec3 inv_dir;
+};
+
+// Decodes the bounds of a compact node bit exactly like CompactBVHNode::aabb.
+// The scale is built from its bits since exp2 is not exact, which also makes extent * scale
+// exact, so contracting it into an fma does not change the result.
+void compact_node_aabb(CompactBVHNode node, out vec3 aabb_min, out vec3 aabb_max){
+    float scale = uintBitsToFloat((node.extent_z_exp >> 16) << 23);
+    vec3 extent = vec3(node.extent_xy & 0xffffu, node.extent_xy >> 16, node.extent_z_exp & 0xffffu);
+    aabb_min = vec3(node.min[0], node.min[1], node.min[2]);
+    aabb_max = aabb_min + extent * scale;
+}
+
+// Correctly rounded a / b like on the CPU (as long as denormals are preserved).
+// Vulkan only bounds divisions to 2.5 ulp and fixes neither their rounding mode nor the one of
+// the conversion to float without RoundingModeRTE, so the quotient in double precision only
+// lies within an ulp of a / b once converted. Of it and its neighbours the float q with the
+// smallest remainder a - q * b is chosen. The remainders are exact in double precision since q
+// and b have 24 bit significands, so neither the rounding mode nor contraction into an fma
+// changes them. A quotient of floats never lies exactly between two floats, so the nearest
+// one is unique.
+float div(float a, float b){
+    float q = float(double(a) / double(b));
+    uint bits = floatBitsToUint(q);
+    // Zero and non-finite quotients are exact.
+    if ((bits & 0x7fffffffu) == 0u || (bits & 0x7f800000u) == 0x7f800000u){
+        return q;
+    }
+    float best = q;
+    double best_remainder = abs(double(a) - double(q) * double(b));
+    float neighbours[2] = float[2](uintBitsToFloat(bits - 1u), uintBitsToFloat(bits + 1u));
+    for (int i = 0; i < 2; i++){
+        double remainder = abs(double(a) - double(neighbours[i]) * double(b));
+        if (remainder < best_remainder){
+            best = neighbours[i];
+            best_remainder = remainder;
+        }
+    }
+    return best;
+}
+
+// Mirrors AABB::intersect_ray for finite rays.
+bool intersect_aabb(vec3 aabb_min, vec3 aabb_max, Ray ray, float tmin, float tmax){
+    for (int axis = 0; axis < 3; axis++){
+        if (aabb_min[axis] > aabb_max[axis]){
+            return false;
+        }
+        if (ray.dir[axis] == 0.0){
+            // The ray is parallel to the slab and either always or never inside of it.
+            if (ray.origin[axis] < aabb_min[axis] || ray.origin[axis] > aabb_max[axis]){
+                return false;
+            }
+            continue;
+        }
+        PRECISE float t0 = (aabb_min[axis] - ray.origin[axis]) * ray.inv_dir[axis];
+        PRECISE float t1 = (aabb_max[axis] - ray.origin[axis]) * ray.inv_dir[axis];
+        float t_near = t0 < t1 ? t0 : t1;
+        float t_far = t0 < t1 ? t1 : t0;
+        PRECISE float t_far_scaled = t_far * SLAB_TFAR_SCALE;
+        tmin = max(tmin, t_near);
+        tmax = min(tmax, t_far_scaled);
+    }
+    return tmin <= tmax;
+}
+
+// Mirrors intersect_triangle, the watertight test of Woop et al. including the fallback to
+// double precision at the edges.
+bool intersect_triangle(
+    Ray ray, vec3 v0, vec3 v1, vec3 v2, float tmin, float tmax,
+    out float t, out vec3 barycentrics
+){
+    // Calculate the dimension where the ray direction is maximal.
+    vec3 abs_dir = abs(ray.dir);
+    int kz = abs_dir.x > abs_dir.y && abs_dir.x > abs_dir.z ? 0 : (abs_dir.y > abs_dir.z ? 1 : 2);
+    int kx = (kz + 1) % 3;
+    int ky = (kx + 1) % 3;
+    // Swap kx and ky to preserve the winding direction of the triangle.
+    if (ray.dir[kz] < 0.0){
+        int tmp = kx;
+        kx = ky;
+        ky = tmp;
+    }
+
+    // Shear constants.
+    float sx = div(ray.dir[kx], ray.dir[kz]);
+    float sy = div(ray.dir[ky], ray.dir[kz]);
+    float sz = div(1.0, ray.dir[kz]);
+
+    // Vertices relative to the ray origin.
+    PRECISE vec3 a = v0 - ray.origin;
+    PRECISE vec3 b = v1 - ray.origin;
+    PRECISE vec3 c = v2 - ray.origin;
+
+    // Shear and scale the vertices.
+    PRECISE float ax = a[kx] - sx * a[kz];
+    PRECISE float ay = a[ky] - sy * a[kz];
+    PRECISE float bx = b[kx] - sx * b[kz];
+    PRECISE float by = b[ky] - sy * b[kz];
+    PRECISE float cx = c[kx] - sx * c[kz];
+    PRECISE float cy = c[ky] - sy * c[kz];
+
+    // Scaled barycentric coordinates.
+    PRECISE float u = cx * by - cy * bx;
+    PRECISE float v = ax * cy - ay * cx;
+    PRECISE float w = bx * ay - by * ax;
+
+    // Fall back to double precision at the edges.
+    if (u == 0.0 || v == 0.0 || w == 0.0){
+        u = float(double(cx) * double(by) - double(cy) * double(bx));
+        v = float(double(ax) * double(cy) - double(ay) * double(cx));
+        w = float(double(bx) * double(ay) - double(by) * double(ax));
+    }
+
+    // Both faces are tested, so the edge functions only have to agree in sign.
+    if ((u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0)){
+        return false;
+    }
+
+    PRECISE float det = u + v + w;
+    if (det == 0.0){
+        return false;
+    }
+
+    // Scaled z coordinates of the vertices used to calculate the hit distance.
+    PRECISE float az = sz * a[kz];
+    PRECISE float bz = sz * b[kz];
+    PRECISE float cz = sz * c[kz];
+    PRECISE float t_scaled = u * az + v * bz + w * cz;
+
+    float rcp_det = div(1.0, det);
+    PRECISE float t_hit = t_scaled * rcp_det;
+    if (!(t_hit >= tmin && t_hit <= tmax)){
+        return false;
+    }
+
+    t = t_hit;
+    barycentrics = vec3(u, v, w) * rcp_det;
+    return true;
+}
+
+// Mirrors BVHView::intersect_closest with the triangles of the mesh as primitives, whose extern
+// indices are the first of their three indices. The BVH starts at node_offset in bvh.nodes and
+// its pointers and leaf ranges are relative to node_offset and index_offset (see GlslInstance).
+// Returns the extern index of the closest hit or NO_HIT, tmax and barycentrics are only
+// updated on a hit.
+uint intersect_closest(
+    Ray ray, uint node_offset, uint index_offset, float tmin, inout float tmax,
+    inout vec3 barycentrics
+){
+    uint closest = NO_HIT;
+    // The builders reject empty input, so there always is a root.
+    uint i = node_offset;
+    // The sentinel miss pointer indicates that the ray left the tree.
+    while (i != MISS_SENTINEL){
+        BVHNode node = bvh.nodes[i];
+        uint miss = node.miss == MISS_SENTINEL ? MISS_SENTINEL : node_offset + node.miss;
+        if (intersect_aabb(node.min.xyz, node.max.xyz, ray, tmin, tmax)){
+            if (node.ty == TY_LEAF){
+                for (uint j = node.right; j < node.right + node.count; j++){
+                    uint index = bvh_indices[index_offset + j];
+                    float t;
+                    vec3 hit_barycentrics;
+                    bool hit = intersect_triangle(
+                        ray,
+                        verts[indices[index]].pos.xyz,
+                        verts[indices[index + 1]].pos.xyz,
+                        verts[indices[index + 2]].pos.xyz,
+                        tmin,
+                        tmax,
+                        t,
+                        hit_barycentrics
+                    );
+                    if (hit){
+                        // Shrinking the interval culls every node behind the closest hit.
+                        tmax = t;
+                        closest = index;
+                        barycentrics = hit_barycentrics;
+                    }
+                }
+                i = miss;
+            }
+            else{
+                i += 1;
+            }
+        }
+        else{
+            i = miss;
+        }
+    }
+    return closest;
+}
+
+#if TRACE_TLAS
+// Mirrors TLAS::intersect_closest, the ray is transformed into the space of every instance
+// matching the mask and the BLAS of the instance traversed with intersect_closest.
+// Returns the extern index of the closest hit or NO_HIT and the index of its instance in
+// instance.
+uint intersect_closest_tlas(
+    Ray ray, uint mask, float tmin, float tmax, out uint instance, inout vec3 barycentrics
+){
+    uint closest = NO_HIT;
+    instance = NO_HIT;
+    uint i = 0;
+    while (i != MISS_SENTINEL){
+        BVHNode node = tlas.nodes[i];
+        if (intersect_aabb(node.min.xyz, node.max.xyz, ray, tmin, tmax)){
+            if (node.ty == TY_LEAF){
+                for (uint j = node.right; j < node.right + node.count; j++){
+                    uint index = tlas_indices[j];
+                    Instance inst = instances[index];
+                    if ((inst.mask & mask) == 0u){
+                        continue;
+                    }
+                    // Mirrors TLAS::object_ray, the direction is not normalised so the
+                    // distances along the ray stay the same.
+                    Ray object_ray;
+                    for (int row = 0; row < 3; row++){
+                        vec4 m = inst.world_to_object[row];
+                        PRECISE float origin = m.x * ray.origin.x + m.y * ray.origin.y
+                            + m.z * ray.origin.z + m.w;
+                        PRECISE float dir = m.x * ray.dir.x + m.y * ray.dir.y + m.z * ray.dir.z;
+                        object_ray.origin[row] = origin;
+                        object_ray.dir[row] = dir;
+                    }
+                    object_ray.inv_dir = vec3(
+                        div(1.0, object_ray.dir.x),
+                        div(1.0, object_ray.dir.y),
+                        div(1.0, object_ray.dir.z)
+                    );
+                    uint hit = intersect_closest(
+                        object_ray,
+                        inst.node_offset,
+                        inst.index_offset,
+                        tmin,
+                        tmax,
+                        barycentrics
+                    );
+                    if (hit != NO_HIT){
+                        closest = hit;
+                        instance = index;
+                    }
+                }
+                i = node.miss;
+            }
+            else{
+                i += 1;
+            }
+        }
+        else{
+            i = node.miss;
+        }
+    }
+    return closest;
+}
+#endif
 
 void main(){
-    imageStore(dst, ivec2(1, 1), vec4(1.0, 0.0, 0.0, 1.0));
+    ivec2 size = imageSize(dst);
+    uvec2 pixel = gl_GlobalInvocationID.xy;
+    if (pixel.x >= uint(size.x) || pixel.y >= uint(size.y)){
+        return;
+    }
+
+    // Mirrors GlslCamera::ray.
+    float x = float(pixel.x) + 0.5;
+    float y = float(pixel.y) + 0.5;
+    PRECISE vec3 dir = camera.corner.xyz + x * camera.right.xyz + y * camera.down.xyz;
+    Ray ray;
+    ray.origin = camera.origin.xyz;
+    ray.dir = dir;
+    ray.inv_dir = vec3(div(1.0, dir.x), div(1.0, dir.y), div(1.0, dir.z));
+
+    float tmax = uintBitsToFloat(0x7f800000u);
+    vec3 barycentrics = vec3(0.0);
+#if TRACE_TLAS
+    uint instance;
+    uint hit = intersect_closest_tlas(ray, 0xffffffffu, 0.0, tmax, instance, barycentrics);
+#else
+    uint hit = intersect_closest(ray, 0u, 0u, 0.0, tmax, barycentrics);
+#endif
+    hits[pixel.y * uint(size.x) + pixel.x] = hit;
+
+    vec4 color = vec4(0.0, 0.0, 0.0, 1.0);
+    if (hit != NO_HIT){
+        Vert v0 = verts[indices[hit]];
+        Vert v1 = verts[indices[hit + 1]];
+        Vert v2 = verts[indices[hit + 2]];
+        // Vertex colors lit by a light at the camera.
+        vec3 normal = normalize(cross(v1.pos.xyz - v0.pos.xyz, v2.pos.xyz - v0.pos.xyz));
+#if TRACE_TLAS
+        // The transpose of world_to_object brings normals from the space of the instance into
+        // world space.
+        Instance inst = instances[instance];
+        normal = normalize(
+            normal.x * inst.world_to_object[0].xyz
+            + normal.y * inst.world_to_object[1].xyz
+            + normal.z * inst.world_to_object[2].xyz
+        );
+#endif
+        float light = abs(dot(normal, normalize(dir)));
+        vec3 albedo = barycentrics.x * v0.color.rgb
+            + barycentrics.y * v1.color.rgb
+            + barycentrics.z * v2.color.rgb;
+        color.rgb = light * albedo;
+    }
+    imageStore(dst, ivec2(pixel), color);
 }
 
 #endif
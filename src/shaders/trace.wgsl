@@ -1,30 +1,284 @@
+// Miss pointer of the nodes after which the ray leaves the tree (see BVHNode::MISS_SENTINEL).
+const MISS_SENTINEL: u32 = 0xffffffffu;
+// Type of the leaves (see GlslNode::TY_LEAF).
+const TY_LEAF: u32 = 1u;
+// Written to hits for pixels whose ray does not hit the mesh (see NO_HIT).
+const NO_HIT: u32 = 0xffffffffu;
+// 1 + 3 * FLT_EPSILON (see AABB::SLAB_TFAR_SCALE).
+const SLAB_TFAR_SCALE: f32 = 1.00000036;
+
+// The structs are generated from the Rust types (see ShaderLayout) and have to be kept in sync.
 
 struct Vert{
-    pos: [[stride(4)]] vec4<f32>;
-    color: [[stride(4)]] vec4<f32>;
-};
+    pos: vec4<f32>,
+    color: vec4<f32>,
+}
 
 struct BVHNode{
-    min: vec4<f32>;
-    max: vec4<f32>;
-    ty: u32;
-    right: u32;
-    miss: u32;
-    _pad: u32;
-};
-
-[[group(0), binding(0)]]
+    min: vec4<f32>,
+    max: vec4<f32>,
+    ty: u32,
+    right: u32,
+    miss: u32,
+    count: u32,
+}
+
+struct CompactBVHNode{
+    min: array<f32, 3>,
+    extent_xy: u32,
+    extent_z_exp: u32,
+    right: u32,
+    miss: u32,
+    count: u32,
+}
+
+struct Instance{
+    world_to_object: array<vec4<f32>, 3>,
+    node_offset: u32,
+    index_offset: u32,
+    mask: u32,
+    id: u32,
+}
+
+struct Camera{
+    origin: vec4<f32>,
+    corner: vec4<f32>,
+    right: vec4<f32>,
+    down: vec4<f32>,
+}
+
+@group(0) @binding(0)
 var<storage, read_write> bvh: array<BVHNode>;
-[[group(0), binding(1)]]
+@group(0) @binding(1)
 var<storage, read_write> verts: array<Vert>;
-[[group(0), binding(2)]]
+@group(0) @binding(2)
 var<storage, read_write> indices: array<u32>;
+@group(0) @binding(3)
+var<storage, read_write> bvh_indices: array<u32>;
+
+@group(1) @binding(0)
+var dst: texture_storage_2d<rgba8unorm, write>;
+// Extern index of the closest triangle hit through every pixel in row major order or NO_HIT.
+@group(1) @binding(1)
+var<storage, read_write> hits: array<u32>;
+
+@group(2) @binding(0)
+var<uniform> camera: Camera;
+
+struct AABB{
+    min: vec3<f32>,
+    max: vec3<f32>,
+}
+
+// Decodes the bounds of a compact node bit exactly like CompactBVHNode::aabb.
+fn compact_node_aabb(node: CompactBVHNode) -> AABB{
+    let scale = bitcast<f32>((node.extent_z_exp >> 16u) << 23u);
+    let extent = vec3<f32>(
+        f32(node.extent_xy & 0xffffu),
+        f32(node.extent_xy >> 16u),
+        f32(node.extent_z_exp & 0xffffu)
+    );
+    var aabb: AABB;
+    aabb.min = vec3<f32>(node.min[0], node.min[1], node.min[2]);
+    aabb.max = aabb.min + extent * scale;
+    return aabb;
+}
+
+struct Ray{
+    origin: vec3<f32>,
+    dir: vec3<f32>,
+    inv_dir: vec3<f32>,
+}
+
+// Mirrors AABB::intersect_ray for finite rays.
+fn intersect_aabb(
+    aabb_min: vec3<f32>, aabb_max: vec3<f32>, ray: Ray, tmin_in: f32, tmax_in: f32
+) -> bool{
+    var tmin = tmin_in;
+    var tmax = tmax_in;
+    for (var axis = 0; axis < 3; axis++){
+        if (aabb_min[axis] > aabb_max[axis]){
+            return false;
+        }
+        if (ray.dir[axis] == 0.0){
+            // The ray is parallel to the slab and either always or never inside of it.
+            if (ray.origin[axis] < aabb_min[axis] || ray.origin[axis] > aabb_max[axis]){
+                return false;
+            }
+            continue;
+        }
+        let t0 = (aabb_min[axis] - ray.origin[axis]) * ray.inv_dir[axis];
+        let t1 = (aabb_max[axis] - ray.origin[axis]) * ray.inv_dir[axis];
+        tmin = max(tmin, select(t1, t0, t0 < t1));
+        tmax = min(tmax, select(t0, t1, t0 < t1) * SLAB_TFAR_SCALE);
+    }
+    return tmin <= tmax;
+}
+
+struct TriangleHit{
+    t: f32,
+    barycentrics: vec3<f32>,
+}
+
+// Mirrors intersect_triangle, the watertight test of Woop et al., in single precision only.
+// Returns a hit with t < 0 if the triangle is missed.
+fn intersect_triangle(
+    ray: Ray, v0: vec3<f32>, v1: vec3<f32>, v2: vec3<f32>, tmin: f32, tmax: f32
+) -> TriangleHit{
+    var miss: TriangleHit;
+    miss.t = -1.0;
+
+    // Calculate the dimension where the ray direction is maximal.
+    let abs_dir = abs(ray.dir);
+    let kz = select(
+        select(2, 1, abs_dir.y > abs_dir.z),
+        0,
+        abs_dir.x > abs_dir.y && abs_dir.x > abs_dir.z
+    );
+    var kx = (kz + 1) % 3;
+    var ky = (kx + 1) % 3;
+    // Swap kx and ky to preserve the winding direction of the triangle.
+    if (ray.dir[kz] < 0.0){
+        let tmp = kx;
+        kx = ky;
+        ky = tmp;
+    }
+
+    // Shear constants.
+    let sx = ray.dir[kx] / ray.dir[kz];
+    let sy = ray.dir[ky] / ray.dir[kz];
+    let sz = 1.0 / ray.dir[kz];
+
+    // Vertices relative to the ray origin.
+    let a = v0 - ray.origin;
+    let b = v1 - ray.origin;
+    let c = v2 - ray.origin;
+
+    // Shear and scale the vertices.
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    // Scaled barycentric coordinates.
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+
+    // Both faces are tested, so the edge functions only have to agree in sign.
+    if ((u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0)){
+        return miss;
+    }
+
+    let det = u + v + w;
+    if (det == 0.0){
+        return miss;
+    }
+
+    // Scaled z coordinates of the vertices used to calculate the hit distance.
+    let az = sz * a[kz];
+    let bz = sz * b[kz];
+    let cz = sz * c[kz];
+    let t_scaled = u * az + v * bz + w * cz;
+
+    let rcp_det = 1.0 / det;
+    let t = t_scaled * rcp_det;
+    if (!(t >= tmin && t <= tmax)){
+        return miss;
+    }
+
+    var hit: TriangleHit;
+    hit.t = t;
+    hit.barycentrics = vec3<f32>(u, v, w) * rcp_det;
+    return hit;
+}
+
+// Mirrors BVHView::intersect_closest with the triangles of the mesh as primitives, whose extern
+// indices are the first of their three indices.
+// Returns the extern index of the closest hit or NO_HIT.
+fn intersect_closest(
+    ray: Ray, tmin: f32, tmax_in: f32, barycentrics: ptr<function, vec3<f32>>
+) -> u32{
+    var closest = NO_HIT;
+    var tmax = tmax_in;
+    // The builders reject empty input, so there always is a root.
+    var i = 0u;
+    // The sentinel miss pointer indicates that the ray left the tree.
+    while (i != MISS_SENTINEL){
+        let node = bvh[i];
+        if (intersect_aabb(node.min.xyz, node.max.xyz, ray, tmin, tmax)){
+            if (node.ty == TY_LEAF){
+                for (var j = node.right; j < node.right + node.count; j++){
+                    let index = bvh_indices[j];
+                    let hit = intersect_triangle(
+                        ray,
+                        verts[indices[index]].pos.xyz,
+                        verts[indices[index + 1u]].pos.xyz,
+                        verts[indices[index + 2u]].pos.xyz,
+                        tmin,
+                        tmax
+                    );
+                    if (hit.t >= 0.0){
+                        // Shrinking the interval culls every node behind the closest hit.
+                        tmax = hit.t;
+                        closest = index;
+                        *barycentrics = hit.barycentrics;
+                    }
+                }
+                i = node.miss;
+            }
+            else{
+                i += 1u;
+            }
+        }
+        else{
+            i = node.miss;
+        }
+    }
+    return closest;
+}
+
+// Port of trace.glsl. WGSL has neither the precise qualifier nor double precision without
+// extensions, so the rays and hit tests are only approximately those of trace_hits and rays
+// grazing an edge can hit other triangles.
+@compute @workgroup_size(8, 8)
+fn cs_main(@builtin(global_invocation_id) global_id: vec3<u32>){
+    let size = textureDimensions(dst);
+    let pixel = global_id.xy;
+    if (pixel.x >= size.x || pixel.y >= size.y){
+        return;
+    }
 
-[[group(1), binding(0)]]
-var<storage, write> dst: texture_storage_2d<rgba8unorm, write>;
+    // Follows GlslCamera::ray, but the multiplications and additions may be contracted into
+    // fmas, so the direction is not bit identical to it.
+    let x = f32(pixel.x) + 0.5;
+    let y = f32(pixel.y) + 0.5;
+    let dir = camera.corner.xyz + x * camera.right.xyz + y * camera.down.xyz;
+    var ray: Ray;
+    ray.origin = camera.origin.xyz;
+    ray.dir = dir;
+    ray.inv_dir = 1.0 / dir;
 
-[[stage(compute), workgroup_size(1)]]
-fn cs_main([[builtin(global_invocation_id)]] global_id: vec3<u32>){
+    let infinity = bitcast<f32>(0x7f800000u);
+    var barycentrics = vec3<f32>(0.0);
+    let hit = intersect_closest(ray, 0.0, infinity, &barycentrics);
+    hits[pixel.y * size.x + pixel.x] = hit;
 
+    var color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    if (hit != NO_HIT){
+        let v0 = verts[indices[hit]];
+        let v1 = verts[indices[hit + 1u]];
+        let v2 = verts[indices[hit + 2u]];
+        // Vertex colors lit by a light at the camera.
+        let normal = normalize(cross(v1.pos.xyz - v0.pos.xyz, v2.pos.xyz - v0.pos.xyz));
+        let light = abs(dot(normal, normalize(dir)));
+        let albedo = barycentrics.x * v0.color.rgb
+            + barycentrics.y * v1.color.rgb
+            + barycentrics.z * v2.color.rgb;
+        color = vec4<f32>(light * albedo, 1.0);
+    }
+    textureStore(dst, vec2<i32>(pixel), color);
 }
 
@@ -0,0 +1,484 @@
+use crate::aabb::*;
+use crate::bvh::*;
+
+///
+/// A placement of a BLAS in the scene.
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Instance {
+    /// Index of the BLAS in the slice the TLAS was built from.
+    pub blas: usize,
+    /// Row major affine transform from the space of the BLAS into world space.
+    pub transform: [[f32; 4]; 3],
+    /// The instance is only intersected by rays whose mask shares a bit with this one.
+    pub mask: u32,
+    /// User defined id, for example to look up the material of the instance.
+    pub id: u32,
+}
+
+impl Instance {
+    pub const IDENTITY: [[f32; 4]; 3] = [[1., 0., 0., 0.], [0., 1., 0., 0.], [0., 0., 1., 0.]];
+
+    pub fn transform_point(&self, p: [f32; 3]) -> [f32; 3] {
+        transform_point(&self.transform, p)
+    }
+    ///
+    /// The AABB enclosing the transformed AABB, as described by Arvo in Graphics Gems.
+    ///
+    pub fn transform_aabb(&self, aabb: &AABB) -> AABB {
+        let m = &self.transform;
+        let mut result = AABB {
+            min: [m[0][3], m[1][3], m[2][3]],
+            max: [m[0][3], m[1][3], m[2][3]],
+        };
+        for (i, row) in m.iter().enumerate() {
+            for ((&entry, &min), &max) in row.iter().zip(&aabb.min).zip(&aabb.max) {
+                let a = entry * min;
+                let b = entry * max;
+                result.min[i] += a.min(b);
+                result.max[i] += a.max(b);
+            }
+        }
+        result
+    }
+    ///
+    /// The transform from world space into the space of the BLAS or None if the transform is
+    /// singular.
+    ///
+    pub fn inverse_transform(&self) -> Option<[[f32; 4]; 3]> {
+        let m = &self.transform;
+        let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+        };
+        // Transposed cofactor matrix of the linear part.
+        let adj = [
+            [
+                cofactor(1, 2, 1, 2),
+                -cofactor(0, 2, 1, 2),
+                cofactor(0, 1, 1, 2),
+            ],
+            [
+                -cofactor(1, 2, 0, 2),
+                cofactor(0, 2, 0, 2),
+                -cofactor(0, 1, 0, 2),
+            ],
+            [
+                cofactor(1, 2, 0, 1),
+                -cofactor(0, 2, 0, 1),
+                cofactor(0, 1, 0, 1),
+            ],
+        ];
+        let det = m[0][0] * adj[0][0] + m[0][1] * adj[1][0] + m[0][2] * adj[2][0];
+        if det == 0. || !det.is_finite() {
+            return None;
+        }
+        let mut inv = [[0.; 4]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                inv[i][j] = adj[i][j] / det;
+            }
+            inv[i][3] = -(inv[i][0] * m[0][3] + inv[i][1] * m[1][3] + inv[i][2] * m[2][3]);
+        }
+        if inv.iter().flatten().all(|x| x.is_finite()) {
+            Some(inv)
+        } else {
+            None
+        }
+    }
+}
+
+#[inline]
+fn transform_point(m: &[[f32; 4]; 3], p: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|i| m[i][0] * p[0] + m[i][1] * p[1] + m[i][2] * p[2] + m[i][3])
+}
+
+#[inline]
+fn transform_vector(m: &[[f32; 4]; 3], v: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|i| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2])
+}
+
+///
+/// Hit of a primitive in an instance, returned by the TLAS traversal.
+///
+#[derive(Copy, Clone, Debug)]
+pub struct InstanceHit<Index, H> {
+    /// Index of the instance in `TLAS::instances`.
+    pub instance: usize,
+    /// Extern index of the primitive in the BLAS.
+    pub index: Index,
+    pub hit: H,
+}
+
+impl<Index, H: Intersection> Intersection for InstanceHit<Index, H> {
+    #[inline]
+    fn t(&self) -> f32 {
+        self.hit.t()
+    }
+}
+
+///
+/// Top level acceleration structure over instances of bottom level BVHs (BLAS).
+/// The extern indices of the TLAS are the indices of the instances.
+///
+#[derive(Debug)]
+pub struct TLAS<Node: BVHNode<ExternIndex = usize>> {
+    pub bvh: BVH<Node>,
+    pub instances: Vec<Instance>,
+    /// The inverse transforms of the instances.
+    world_to_object: Vec<[[f32; 4]; 3]>,
+}
+
+impl<Node: BVHNode<ExternIndex = usize>> TLAS<Node> {
+    ///
+    /// Builds the TLAS over the AABBs of the BLASes transformed by the instances.
+    /// The BLASes are only needed for their bounds, traversal takes them as an argument so
+    /// they can be stored and uploaded independently.
+    ///
+    pub fn build<BlasNode: BVHNode>(
+        blases: &[BVH<BlasNode>],
+        instances: Vec<Instance>,
+        config: &BuildConfig,
+    ) -> Result<Self, BuildError> {
+        let world_to_object = instances
+            .iter()
+            .enumerate()
+            .map(|(index, instance)| {
+                if instance.blas >= blases.len() {
+                    return Err(BuildError::InvalidInstance { index });
+                }
+                instance
+                    .inverse_transform()
+                    .ok_or(BuildError::InvalidInstance { index })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let bvh = BVH::build(
+            instances
+                .iter()
+                .map(|instance| instance.transform_aabb(&blases[instance.blas].aabb()))
+                .enumerate(),
+            config,
+        )?;
+        Ok(Self {
+            bvh,
+            instances,
+            world_to_object,
+        })
+    }
+    pub fn world_to_object(&self) -> &[[[f32; 4]; 3]] {
+        &self.world_to_object
+    }
+    ///
+    /// Transforms the ray into the space of the instance.
+    /// The direction is not normalised, so distances along the ray stay the same.
+    ///
+    pub fn object_ray(&self, instance: usize, ray: &Ray) -> Ray {
+        let m = &self.world_to_object[instance];
+        Ray::new(transform_point(m, ray.origin), transform_vector(m, ray.dir))
+    }
+    ///
+    /// Finds the closest hit of the ray with the instances whose mask shares a bit with `mask`.
+    ///
+    /// At every instance the ray is transformed into the space of its BLAS, which is then
+    /// traversed with `intersect_closest`. `prim_test` is called with the instance, the extern
+    /// index of the primitive in the BLAS and the transformed ray.
+    ///
+    pub fn intersect_closest<BlasNode, H, F>(
+        &self,
+        blases: &[BVH<BlasNode>],
+        ray: &Ray,
+        mask: u32,
+        tmin: f32,
+        tmax: f32,
+        mut prim_test: F,
+    ) -> Option<InstanceHit<BlasNode::ExternIndex, H>>
+    where
+        BlasNode: BVHNode,
+        H: Intersection,
+        F: FnMut(&Instance, BlasNode::ExternIndex, &Ray, f32, f32) -> Option<H>,
+    {
+        self.bvh
+            .intersect_closest(ray, tmin, tmax, |i, ray, tmin, tmax| {
+                let instance = &self.instances[i];
+                if instance.mask & mask == 0 {
+                    return None;
+                }
+                let ray = self.object_ray(i, ray);
+                blases[instance.blas]
+                    .intersect_closest(&ray, tmin, tmax, |index, ray, tmin, tmax| {
+                        prim_test(instance, index, ray, tmin, tmax)
+                    })
+                    .map(|(index, hit)| InstanceHit {
+                        instance: i,
+                        index,
+                        hit,
+                    })
+            })
+            .map(|hit| hit.1)
+    }
+    ///
+    /// Tests if the ray hits any primitive of the instances matching the mask in the interval
+    /// [0, tmax], see `BVH::intersect_any`.
+    ///
+    pub fn intersect_any<BlasNode, F>(
+        &self,
+        blases: &[BVH<BlasNode>],
+        ray: &Ray,
+        mask: u32,
+        tmax: f32,
+        mut prim_test: F,
+    ) -> bool
+    where
+        BlasNode: BVHNode,
+        F: FnMut(&Instance, BlasNode::ExternIndex, &Ray, f32) -> bool,
+    {
+        self.bvh.intersect_any(ray, tmax, |i, ray, tmax| {
+            let instance = &self.instances[i];
+            if instance.mask & mask == 0 {
+                return false;
+            }
+            let ray = self.object_ray(i, ray);
+            blases[instance.blas].intersect_any(&ray, tmax, |index, ray, tmax| {
+                prim_test(instance, index, ray, tmax)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::glsl_bvh::*;
+    use crate::tlas::*;
+    use crate::triangle::*;
+
+    fn random_triangles(n: usize, seed: u64) -> Vec<[[f32; 3]; 3]> {
+        let mut rng = Lcg(seed);
+        (0..n)
+            .map(|_| {
+                let a = rng.next_vec3(4.);
+                let b = rng.next_vec3(1.);
+                let c = rng.next_vec3(1.);
+                [
+                    a,
+                    [a[0] + b[0], a[1] + b[1], a[2]],
+                    [a[0], a[1] + c[1], a[2] + c[2]],
+                ]
+            })
+            .collect()
+    }
+
+    fn instances() -> Vec<Instance> {
+        vec![
+            Instance {
+                blas: 0,
+                transform: Instance::IDENTITY,
+                mask: 1,
+                id: 10,
+            },
+            // Rotated by 90 degrees around z and moved.
+            Instance {
+                blas: 1,
+                transform: [[0., -1., 0., 10.], [1., 0., 0., 0.], [0., 0., 1., 2.]],
+                mask: 2,
+                id: 11,
+            },
+            // Scaled and moved.
+            Instance {
+                blas: 0,
+                transform: [[2., 0., 0., 0.], [0., 0.5, 0., 6.], [0., 0., 1., 1.]],
+                mask: 1,
+                id: 12,
+            },
+        ]
+    }
+
+    ///
+    /// Closest hit traversing the flattened buffers like trace.glsl with `TRACE_TLAS`.
+    /// The pointers and leaf ranges of a BLAS are relative to the offsets of the instance.
+    ///
+    fn intersect_buffers(
+        tlas: &GlslTLAS,
+        buffers: &GlslTLASBuffers,
+        meshes: &[Vec<[[f32; 3]; 3]>],
+        ray: &Ray,
+        mask: u32,
+    ) -> Option<InstanceHit<usize, TriangleHit>> {
+        let tlas_indices: Vec<usize> = buffers.indices.iter().map(|&i| i as usize).collect();
+        let blas_indices: Vec<usize> = buffers.blas_indices.iter().map(|&i| i as usize).collect();
+        BVHView::new(&buffers.nodes, &tlas_indices)
+            .intersect_closest(ray, 0., f32::INFINITY, |i, ray, tmin, tmax| {
+                let instance = &buffers.instances[i];
+                if instance.mask & mask == 0 {
+                    return None;
+                }
+                let m = &instance.world_to_object;
+                let ray = Ray::new(transform_point(m, ray.origin), transform_vector(m, ray.dir));
+                let mesh = &meshes[tlas.instances[i].blas];
+                BVHView::new(
+                    &buffers.blas_nodes[instance.node_offset as usize..],
+                    &blas_indices[instance.index_offset as usize..],
+                )
+                .intersect_closest(&ray, tmin, tmax, |index, ray, tmin, tmax| {
+                    intersect_triangle(ray, mesh[index], tmin, tmax)
+                })
+                .map(|(index, hit)| InstanceHit {
+                    instance: i,
+                    index,
+                    hit,
+                })
+            })
+            .map(|hit| hit.1)
+    }
+
+    #[test]
+    fn test_inverse_transform() {
+        for instance in instances() {
+            let inv = instance.inverse_transform().unwrap();
+            let p = [1., 2., 3.];
+            let q = transform_point(&inv, instance.transform_point(p));
+            for axis in 0..3 {
+                assert!((p[axis] - q[axis]).abs() < 1e-5);
+            }
+        }
+        let singular = Instance {
+            transform: [[1., 0., 0., 0.], [0., 1., 0., 0.], [0., 0., 0., 0.]],
+            ..instances()[0]
+        };
+        assert_eq!(singular.inverse_transform(), None);
+    }
+
+    #[test]
+    fn test_transform_aabb() {
+        let aabb = AABB {
+            min: [0., 0., 0.],
+            max: [1., 2., 3.],
+        };
+        let instance = instances()[1];
+        let result = instance.transform_aabb(&aabb);
+        assert_eq!(result.min, [8., 0., 2.]);
+        assert_eq!(result.max, [10., 1., 5.]);
+    }
+
+    #[test]
+    fn test_tlas() {
+        let meshes = [random_triangles(200, 27), random_triangles(100, 28)];
+        let blases: Vec<GlslBVH> = meshes
+            .iter()
+            .map(|tris| {
+                GlslBVH::build(
+                    tris.iter().map(|&tri| AABB::from(tri)).enumerate(),
+                    &BINNED_8,
+                )
+                .unwrap()
+            })
+            .collect();
+        let tlas = GlslTLAS::build(&blases, instances(), &BINNED_8).unwrap();
+        assert_eq!(tlas.bvh.validate(), Ok(()));
+
+        let buffers = tlas.glsl_buffers(&blases).unwrap();
+        assert_eq!(std::mem::size_of::<GlslInstance>(), 64);
+        assert_eq!(buffers.instances[0].node_offset, 0);
+        assert_eq!(
+            buffers.instances[1].node_offset,
+            blases[0].nodes().len() as u32
+        );
+        assert_eq!(buffers.instances[1].index_offset, 200);
+        assert_eq!(buffers.instances[2].id, 12);
+        assert_eq!(buffers.blas_indices.len(), 300);
+        assert_eq!(buffers.nodes.len(), tlas.bvh.nodes().len());
+
+        // Brute force over the triangles transformed into world space.
+        let world_tris: Vec<(usize, usize, [[f32; 3]; 3])> = tlas
+            .instances
+            .iter()
+            .enumerate()
+            .flat_map(|(i, instance)| {
+                meshes[instance.blas]
+                    .iter()
+                    .enumerate()
+                    .map(move |(j, tri)| (i, j, tri.map(|v| instance.transform_point(v))))
+            })
+            .collect();
+
+        let mut rng = Lcg(29);
+        for mask in [1, 2, 3] {
+            for _ in 0..300 {
+                let origin = [-5., rng.next_f32() * 12., rng.next_f32() * 8.];
+                let target = [15., rng.next_f32() * 12., rng.next_f32() * 8.];
+                let ray = Ray::new(
+                    origin,
+                    [
+                        target[0] - origin[0],
+                        target[1] - origin[1],
+                        target[2] - origin[2],
+                    ],
+                );
+                let expected = world_tris
+                    .iter()
+                    .filter(|(i, _, _)| tlas.instances[*i].mask & mask != 0)
+                    .filter_map(|(i, j, tri)| {
+                        intersect_triangle(&ray, *tri, 0., f32::INFINITY).map(|hit| (*i, *j, hit.t))
+                    })
+                    .min_by(|a, b| a.2.total_cmp(&b.2));
+                let hit = tlas.intersect_closest(
+                    &blases,
+                    &ray,
+                    mask,
+                    0.,
+                    f32::INFINITY,
+                    |instance, index, ray, tmin, tmax| {
+                        intersect_triangle(ray, meshes[instance.blas][index], tmin, tmax)
+                    },
+                );
+                let buffer_hit = intersect_buffers(&tlas, &buffers, &meshes, &ray, mask);
+                assert_eq!(
+                    buffer_hit.map(|hit| (hit.instance, hit.index, hit.hit.t)),
+                    hit.map(|hit| (hit.instance, hit.index, hit.hit.t))
+                );
+                match (hit, expected) {
+                    (Some(hit), Some(expected)) => {
+                        assert_eq!((hit.instance, hit.index), (expected.0, expected.1));
+                        assert!((hit.hit.t - expected.2).abs() < 1e-4);
+                    }
+                    (hit, expected) => assert_eq!(hit.is_some(), expected.is_some()),
+                }
+
+                let any =
+                    tlas.intersect_any(&blases, &ray, mask, 1., |instance, index, ray, tmax| {
+                        intersect_triangle(ray, meshes[instance.blas][index], 0., tmax).is_some()
+                    });
+                assert_eq!(any, expected.is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_tlas_errors() {
+        let blases =
+            vec![GlslBVH::build(random_aabbs(10, 30).into_iter().enumerate(), &SWEEP).unwrap()];
+        let mut invalid = instances();
+        assert_eq!(
+            GlslTLAS::build(&blases, invalid.clone(), &SWEEP).unwrap_err(),
+            BuildError::InvalidInstance { index: 1 }
+        );
+        invalid[1].blas = 0;
+        invalid[2].transform[1][1] = 0.;
+        assert_eq!(
+            GlslTLAS::build(&blases, invalid, &SWEEP).unwrap_err(),
+            BuildError::InvalidInstance { index: 2 }
+        );
+        assert_eq!(
+            GlslTLAS::build(&blases, vec![], &SWEEP).unwrap_err(),
+            BuildError::EmptyInput
+        );
+
+        // The buffers have to be created from the BLASes the TLAS was built with.
+        let mut valid = instances();
+        valid[1].blas = 0;
+        let tlas = GlslTLAS::build(&blases, valid, &SWEEP).unwrap();
+        assert_eq!(
+            tlas.glsl_buffers(&[]).err(),
+            Some(BuildError::InvalidInstance { index: 0 })
+        );
+    }
+}
@@ -13,17 +13,63 @@ impl PipelineLayout for TracePipeline{
             bind_group_layouts: &[
                 &TraceMesh::bind_group_layout(device),
                 &DstImage::bind_group_layout(device),
+                &TraceCamera::bind_group_layout(device),
             ],
             push_constant_ranges: &[]
         }))
     }
 }
 
+#[derive(Debug)]
+pub enum TracePipelineError {
+    /// The device was created without these of `TracePipeline::FEATURES`.
+    MissingFeatures(wgpu::Features),
+}
+
+impl std::fmt::Display for TracePipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TracePipelineError::MissingFeatures(features) => {
+                write!(f, "the device lacks the features {:?}", features)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TracePipelineError {}
+
 impl TracePipeline{
-    pub fn load(device: &wgpu::Device) -> Self{
+    ///
+    /// Features the device has to be requested with.
+    /// The shader divides and falls back to double precision to find the same hits as
+    /// `trace_hits`.
+    ///
+    pub const FEATURES: wgpu::Features = wgpu::Features::SHADER_FLOAT64;
+
+    ///
+    /// Fails if the device lacks any of `FEATURES`.
+    /// The shader runs in workgroups of 8x8 pixels.
+    ///
+    pub fn load(device: &wgpu::Device) -> Result<Self, TracePipelineError>{
+        Self::load_with_defines(device, None)
+    }
+    ///
+    /// Loads trace.glsl with `TRACE_TLAS`, which traces the scene bound as `TraceTLAS` in place
+    /// of `TraceMesh`.
+    ///
+    pub fn load_tlas(device: &wgpu::Device) -> Result<Self, TracePipelineError>{
+        let defines = HashMap::from([("TRACE_TLAS", "1")]);
+        Self::load_with_defines(device, Some(&defines))
+    }
+    fn load_with_defines(device: &wgpu::Device, defines: Option<&HashMap<&str, &str>>) -> Result<Self, TracePipelineError>{
+        let missing = Self::FEATURES - device.features();
+        if !missing.is_empty() {
+            return Err(TracePipelineError::MissingFeatures(missing));
+        }
+
         //let shader = Shader::load(device, &std::path::Path::new("src/shaders/trace.glsl"), wgpu::ShaderStages::COMPUTE, None).unwrap();
         
-        let shader = ComputeShader::from_src_glsl(device, include_str!("shaders/trace.glsl"), None).unwrap();
+        let shader = ComputeShader::from_src_glsl(device, include_str!("shaders/trace.glsl"), defines).unwrap();
         /*
         let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor{
             label: None,
@@ -38,7 +84,7 @@ impl TracePipeline{
             //..shader.compute_pipeline_desc()
         });
 
-        Self(cppl)
+        Ok(Self(cppl))
     }
 }
 
@@ -47,10 +93,12 @@ pub struct TraceMesh{
     nodes: Buffer<GlslBVHNode>,
     verts: Buffer<Vert>,
     indices: Buffer<u32>,
+    bvh_indices: Buffer<u32>,
 }
 
 impl TraceMesh{
-    pub fn new(device: &wgpu::Device, nodes: &[GlslBVHNode], verts: &[Vert], indices: &[u32]) -> Self{
+    pub fn new(device: &wgpu::Device, bvh: &GlslBVH, verts: &[Vert], indices: &[u32]) -> Self{
+        let nodes = bvh.nodes();
         let nodes = BufferBuilder::new()
             .storage()
             .build(device, nodes);
@@ -60,11 +108,15 @@ impl TraceMesh{
         let indices = BufferBuilder::new()
             .storage()
             .build(device, indices);
+        let bvh_indices = BufferBuilder::new()
+            .storage()
+            .build(device, &bvh.glsl_indices().expect("the indices of the BVH exceed u32"));
 
         Self{
             nodes,
             verts,
             indices,
+            bvh_indices,
         }
     }
 }
@@ -86,14 +138,87 @@ impl BindGroupLayout for TraceMesh{
                     binding: 2,
                     ..glsl::buffer_entry(true)
                 },
+                wgpu::BindGroupLayoutEntry{
+                    binding: 3,
+                    ..glsl::buffer_entry(true)
+                },
             ]
         })
     }
 }
 
+///
+/// Set 0 of trace.glsl with `TRACE_TLAS`, mirroring `GlslTLASBuffers`.
+/// The concatenated BLASes are bound in place of the BVH of `TraceMesh`, `verts` and `indices`
+/// hold the concatenated meshes.
+///
+#[derive(BindGroupContent)]
+pub struct TraceTLAS{
+    blas_nodes: Buffer<GlslBVHNode>,
+    verts: Buffer<Vert>,
+    indices: Buffer<u32>,
+    blas_indices: Buffer<u32>,
+    nodes: Buffer<GlslBVHNode>,
+    tlas_indices: Buffer<u32>,
+    instances: Buffer<GlslInstance>,
+}
+
+impl TraceTLAS{
+    pub fn new(device: &wgpu::Device, buffers: &GlslTLASBuffers, verts: &[Vert], indices: &[u32]) -> Self{
+        let blas_nodes = BufferBuilder::new()
+            .storage()
+            .build(device, &buffers.blas_nodes);
+        let verts = BufferBuilder::new()
+            .storage()
+            .build(device, verts);
+        let indices = BufferBuilder::new()
+            .storage()
+            .build(device, indices);
+        let blas_indices = BufferBuilder::new()
+            .storage()
+            .build(device, &buffers.blas_indices);
+        let nodes = BufferBuilder::new()
+            .storage()
+            .build(device, &buffers.nodes);
+        let tlas_indices = BufferBuilder::new()
+            .storage()
+            .build(device, &buffers.indices);
+        let instances = BufferBuilder::new()
+            .storage()
+            .build(device, &buffers.instances);
+
+        Self{
+            blas_nodes,
+            verts,
+            indices,
+            blas_indices,
+            nodes,
+            tlas_indices,
+            instances,
+        }
+    }
+}
+
+impl BindGroupLayout for TraceTLAS{
+    fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let entries: Vec<wgpu::BindGroupLayoutEntry> = (0..7)
+            .map(|binding| wgpu::BindGroupLayoutEntry{
+                binding,
+                ..glsl::buffer_entry(true)
+            })
+            .collect();
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor{
+            label: Some("TraceTLAS BindGroupLayout"),
+            entries: &entries,
+        })
+    }
+}
+
 #[derive(BindGroupContent)]
 pub struct DstImage{
     pub view: wgpu::TextureView,
+    /// Extern index of the triangle hit through every pixel or `NO_HIT` (see `trace_hits`).
+    pub hits: Buffer<u32>,
 }
 
 impl BindGroupLayout for DstImage{
@@ -111,6 +236,47 @@ impl BindGroupLayout for DstImage{
                         view_dimension: wgpu::TextureViewDimension::D2,
                     }
                     //..glsl::image2D_entry(wgpu::TextureFormat::Rgba8Unorm, wgpu::StorageTextureAccess::WriteOnly)
+                },
+                wgpu::BindGroupLayoutEntry{
+                    binding: 1,
+                    ..glsl::buffer_entry(false)
+                },
+            ]
+        })
+    }
+}
+
+#[derive(BindGroupContent)]
+pub struct TraceCamera{
+    camera: Buffer<GlslCamera>,
+}
+
+impl TraceCamera{
+    pub fn new(device: &wgpu::Device, camera: &GlslCamera) -> Self{
+        let camera = BufferBuilder::new()
+            .uniform()
+            .build(device, &[*camera]);
+
+        Self{
+            camera,
+        }
+    }
+}
+
+impl BindGroupLayout for TraceCamera{
+    fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor{
+            label: Some("Trace Camera BindGroupLayout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry{
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    count: None,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    }
                 }
             ]
         })
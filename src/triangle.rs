@@ -0,0 +1,177 @@
+use crate::aabb::*;
+use crate::bvh::*;
+
+#[derive(Copy, Clone, Debug)]
+pub struct TriangleHit {
+    pub t: f32,
+    /// Barycentric coordinates of the hit point in respect to the three vertices.
+    pub barycentrics: [f32; 3],
+    /// True if the ray hit the counter clockwise side of the triangle.
+    pub front_face: bool,
+}
+
+///
+/// Gives builders access to the vertices of the triangles referenced by the extern indices,
+/// for when the AABBs of the primitives are not enough (see `BVH::build_triangles`).
+///
+pub trait Triangles<Index> {
+    fn triangle(&self, index: Index) -> [[f32; 3]; 3];
+}
+
+impl Intersection for TriangleHit {
+    #[inline]
+    fn t(&self) -> f32 {
+        self.t
+    }
+}
+
+///
+/// Watertight ray triangle intersection as described by Woop et al.
+/// https://jcgt.org/published/0002/01/05/paper.pdf
+///
+/// The vertices are transformed into a ray space, in which the ray starts at the origin and
+/// points along the z axis. The edge functions are then evaluated in 2D, which guarantees that
+/// rays hitting an edge shared by two triangles always hit at least one of them.
+/// Edge functions that evaluate to exactly 0 are recomputed in double precision.
+///
+pub fn intersect_triangle(
+    ray: &Ray,
+    tri: [[f32; 3]; 3],
+    tmin: f32,
+    tmax: f32,
+) -> Option<TriangleHit> {
+    // Calculate the dimension where the ray direction is maximal.
+    let abs_dir = [ray.dir[0].abs(), ray.dir[1].abs(), ray.dir[2].abs()];
+    let kz = if abs_dir[0] > abs_dir[1] && abs_dir[0] > abs_dir[2] {
+        0
+    } else if abs_dir[1] > abs_dir[2] {
+        1
+    } else {
+        2
+    };
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    // Swap kx and ky to preserve the winding direction of the triangle.
+    if ray.dir[kz] < 0. {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    // Shear constants.
+    let sx = ray.dir[kx] / ray.dir[kz];
+    let sy = ray.dir[ky] / ray.dir[kz];
+    let sz = 1. / ray.dir[kz];
+
+    // Vertices relative to the ray origin.
+    let a = sub(tri[0], ray.origin);
+    let b = sub(tri[1], ray.origin);
+    let c = sub(tri[2], ray.origin);
+
+    // Shear and scale the vertices.
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    // Scaled barycentric coordinates.
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+
+    // Fall back to double precision at the edges.
+    if u == 0. || v == 0. || w == 0. {
+        u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+        v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+        w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+    }
+
+    // Both faces are tested, so the edge functions only have to agree in sign.
+    if (u < 0. || v < 0. || w < 0.) && (u > 0. || v > 0. || w > 0.) {
+        return None;
+    }
+
+    let det = u + v + w;
+    if det == 0. {
+        return None;
+    }
+
+    // Scaled z coordinates of the vertices used to calculate the hit distance.
+    let az = sz * a[kz];
+    let bz = sz * b[kz];
+    let cz = sz * c[kz];
+    let t_scaled = u * az + v * bz + w * cz;
+
+    let rcp_det = 1. / det;
+    let t = t_scaled * rcp_det;
+    if !(t >= tmin && t <= tmax) {
+        return None;
+    }
+
+    Some(TriangleHit {
+        t,
+        barycentrics: [u * rcp_det, v * rcp_det, w * rcp_det],
+        front_face: det > 0.,
+    })
+}
+
+#[inline]
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+#[cfg(test)]
+mod test {
+    use crate::triangle::*;
+
+    const TRI: [[f32; 3]; 3] = [[0., 0., 1.], [1., 0., 1.], [0., 1., 1.]];
+
+    #[test]
+    fn test_intersect_triangle() {
+        let ray = Ray::new([0.25, 0.5, 0.], [0., 0., 1.]);
+        let hit = intersect_triangle(&ray, TRI, 0., f32::INFINITY).unwrap();
+        assert_eq!(hit.t, 1.);
+        assert_eq!(hit.barycentrics, [0.25, 0.25, 0.5]);
+        // The normal of TRI points along the ray.
+        assert!(!hit.front_face);
+
+        let ray = Ray::new([0.25, 0.5, 2.], [0., 0., -1.]);
+        let hit = intersect_triangle(&ray, TRI, 0., f32::INFINITY).unwrap();
+        assert_eq!(hit.t, 1.);
+        assert!(hit.front_face);
+
+        // Outside of the triangle and outside of the interval.
+        let ray = Ray::new([0.75, 0.75, 0.], [0., 0., 1.]);
+        assert!(intersect_triangle(&ray, TRI, 0., f32::INFINITY).is_none());
+        let ray = Ray::new([0.25, 0.5, 0.], [0., 0., 1.]);
+        assert!(intersect_triangle(&ray, TRI, 0., 0.5).is_none());
+    }
+
+    #[test]
+    fn test_watertight() {
+        // A quad split along its diagonal. Every ray through the diagonal has to hit one of the
+        // two triangles.
+        let quad = [
+            [-1.3, -0.7, 1.1],
+            [1.7, -0.9, 1.3],
+            [1.1, 1.9, 0.7],
+            [-0.9, 1.3, 1.2],
+        ];
+        let t0 = [quad[0], quad[1], quad[2]];
+        let t1 = [quad[0], quad[2], quad[3]];
+        let n = 1000;
+        for i in 1..n {
+            let s = i as f32 / n as f32;
+            let p = [
+                quad[0][0] + (quad[2][0] - quad[0][0]) * s,
+                quad[0][1] + (quad[2][1] - quad[0][1]) * s,
+                quad[0][2] + (quad[2][2] - quad[0][2]) * s,
+            ];
+            let origin = [0.1, 0.2, -3.];
+            let ray = Ray::new(origin, [p[0] - origin[0], p[1] - origin[1], p[2] - origin[2]]);
+            let hit0 = intersect_triangle(&ray, t0, 0., f32::INFINITY);
+            let hit1 = intersect_triangle(&ray, t1, 0., f32::INFINITY);
+            assert!(hit0.is_some() || hit1.is_some());
+        }
+    }
+}
@@ -0,0 +1,487 @@
+use crate::aabb::*;
+use crate::bvh::*;
+use std::ops::Range;
+
+///
+/// Upper bound for the width of wide nodes, used to size the buffers of the traversal.
+///
+pub const MAX_WIDTH: usize = 16;
+
+///
+/// What a slot of a wide node points to.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WideChild {
+    /// Index of a wide node.
+    Node(usize),
+    /// Range of the primitives in the indices of the BVH.
+    Leaf(Range<usize>),
+    /// Unused slot.
+    Empty,
+}
+
+///
+/// Wide analogue of `BVHNode`: a node holding the bounds of up to `WIDTH` children.
+///
+pub trait WideBVHNode: Copy {
+    const WIDTH: usize;
+    /// Largest node or primitive index the node type is able to store.
+    const MAX_INDEX: usize = usize::MAX;
+    /// A node with all slots empty.
+    fn empty() -> Self;
+    fn set_child(&mut self, slot: usize, aabb: AABB, child: WideChild);
+    fn child(&self, slot: usize) -> WideChild;
+    ///
+    /// Writes the entry distance of the ray into the AABB of every child to `t`, or infinity if
+    /// the ray misses it in the interval [tmin, tmax]. Empty slots are always missed.
+    /// Has to agree with `AABB::intersect_ray` for every child.
+    ///
+    fn intersect_children(&self, ray: &Ray, tmin: f32, tmax: f32, t: &mut [f32]);
+}
+
+///
+/// Node with `N` children, storing their bounds in SoA layout so that all of them can be tested
+/// at once with SIMD instructions.
+/// The node is `Pod` and can be uploaded as is, it takes 32 bytes per child.
+///
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct WideNode<const N: usize> {
+    pub min_x: [f32; N],
+    pub min_y: [f32; N],
+    pub min_z: [f32; N],
+    pub max_x: [f32; N],
+    pub max_y: [f32; N],
+    pub max_z: [f32; N],
+    /// Index of the child node or the first index of the leaf, `EMPTY` for unused slots.
+    pub child: [u32; N],
+    /// Number of primitives in the leaf, 0 for inner nodes and unused slots.
+    pub count: [u32; N],
+}
+
+// Safety: all fields are arrays of 4 byte values, so there is no padding for any N.
+unsafe impl<const N: usize> bytemuck::Zeroable for WideNode<N> {}
+unsafe impl<const N: usize> bytemuck::Pod for WideNode<N> {}
+
+impl<const N: usize> WideNode<N> {
+    pub const EMPTY: u32 = u32::MAX;
+}
+
+pub type BVH4Node = WideNode<4>;
+pub type BVH8Node = WideNode<8>;
+
+impl<const N: usize> WideBVHNode for WideNode<N> {
+    const WIDTH: usize = N;
+    const MAX_INDEX: usize = u32::MAX as usize - 1;
+
+    fn empty() -> Self {
+        let empty = AABB::empty();
+        Self {
+            min_x: [empty.min[0]; N],
+            min_y: [empty.min[1]; N],
+            min_z: [empty.min[2]; N],
+            max_x: [empty.max[0]; N],
+            max_y: [empty.max[1]; N],
+            max_z: [empty.max[2]; N],
+            child: [Self::EMPTY; N],
+            count: [0; N],
+        }
+    }
+
+    #[inline]
+    fn set_child(&mut self, slot: usize, aabb: AABB, child: WideChild) {
+        let aabb = match child {
+            WideChild::Empty => AABB::empty(),
+            _ => aabb,
+        };
+        self.min_x[slot] = aabb.min[0];
+        self.min_y[slot] = aabb.min[1];
+        self.min_z[slot] = aabb.min[2];
+        self.max_x[slot] = aabb.max[0];
+        self.max_y[slot] = aabb.max[1];
+        self.max_z[slot] = aabb.max[2];
+        let (child, count) = match child {
+            WideChild::Node(index) => (index as u32, 0),
+            WideChild::Leaf(range) => (range.start as u32, range.len() as u32),
+            WideChild::Empty => (Self::EMPTY, 0),
+        };
+        self.child[slot] = child;
+        self.count[slot] = count;
+    }
+
+    #[inline]
+    fn child(&self, slot: usize) -> WideChild {
+        let child = self.child[slot];
+        let count = self.count[slot];
+        if child == Self::EMPTY {
+            WideChild::Empty
+        } else if count == 0 {
+            WideChild::Node(child as usize)
+        } else {
+            WideChild::Leaf((child as usize)..((child + count) as usize))
+        }
+    }
+
+    fn intersect_children(&self, ray: &Ray, tmin: f32, tmax: f32, t: &mut [f32]) {
+        let mut t_near = [tmin; N];
+        let mut t_far = [tmax; N];
+        let mut hit = [!(tmin.is_nan() || tmax.is_nan()); N];
+        if ray.origin.iter().chain(ray.dir.iter()).any(|x| x.is_nan()) {
+            hit = [false; N];
+        }
+        let mins = [&self.min_x, &self.min_y, &self.min_z];
+        let maxs = [&self.max_x, &self.max_y, &self.max_z];
+        for axis in 0..3 {
+            let (min, max) = (mins[axis], maxs[axis]);
+            let origin = ray.origin[axis];
+            if ray.dir[axis] == 0. {
+                // The ray is parallel to the slabs and either always or never inside of them.
+                for i in 0..N {
+                    hit[i] &= min[i] <= max[i] && origin >= min[i] && origin <= max[i];
+                }
+                continue;
+            }
+            let inv_dir = ray.inv_dir[axis];
+            for i in 0..N {
+                let t0 = (min[i] - origin) * inv_dir;
+                let t1 = (max[i] - origin) * inv_dir;
+                let (near, far) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+                t_near[i] = t_near[i].max(near);
+                t_far[i] = t_far[i].min(far * AABB::SLAB_TFAR_SCALE);
+                hit[i] &= min[i] <= max[i];
+            }
+        }
+        for i in 0..N {
+            t[i] = if hit[i] && t_near[i] <= t_far[i] {
+                t_near[i]
+            } else {
+                f32::INFINITY
+            };
+        }
+    }
+}
+
+///
+/// BVH with wide nodes collapsed from a binary BVH.
+/// Unlike the binary BVH it is traversed with a stack, visiting the children of a node front to
+/// back. The root is at index 0.
+///
+#[derive(Debug)]
+pub struct WideBVH<Node: WideBVHNode, Index> {
+    pub nodes: Vec<Node>,
+    pub indices: Vec<Index>,
+    aabb: AABB,
+}
+
+pub type BVH4<Index> = WideBVH<BVH4Node, Index>;
+pub type BVH8<Index> = WideBVH<BVH8Node, Index>;
+
+impl<Node: WideBVHNode, Index: Copy> WideBVH<Node, Index> {
+    ///
+    /// Collapses the binary BVH into wide nodes. The leaves and indices are kept as they are.
+    /// Fails with `EmptyInput` if the binary BVH has no nodes, which the builders never return
+    /// but `BVH::from_raw_parts` accepts.
+    ///
+    /// Every wide node starts with the two children of a binary node and repeatedly replaces
+    /// the inner child with the largest surface area by its two children until all slots are
+    /// used, which pulls the nodes most likely to be visited into their parents.
+    ///
+    pub fn from_binary<B: BVHNode<ExternIndex = Index>>(bvh: &BVH<B>) -> Result<Self, BuildError> {
+        // The traversal buffers hold MAX_WIDTH children.
+        assert!(
+            Node::WIDTH >= 2 && Node::WIDTH <= MAX_WIDTH,
+            "the width of a wide node has to lie in [2, {}]",
+            MAX_WIDTH
+        );
+        if bvh.nodes().is_empty() {
+            return Err(BuildError::EmptyInput);
+        }
+        let count = bvh.nodes().len().max(bvh.indices().len());
+        if count > Node::MAX_INDEX {
+            return Err(BuildError::TooManyPrimitives { count });
+        }
+        let mut nodes = Vec::new();
+        let root = &bvh.nodes()[0];
+        if root.is_leaf() {
+            let mut node = Node::empty();
+            node.set_child(0, root.aabb(), WideChild::Leaf(root.range()));
+            nodes.push(node);
+        } else {
+            Self::collapse(bvh, 0, &mut nodes);
+        }
+        Ok(Self {
+            nodes,
+            indices: bvh.indices().to_vec(),
+            aabb: bvh.aabb(),
+        })
+    }
+    fn collapse<B: BVHNode<ExternIndex = Index>>(
+        bvh: &BVH<B>,
+        index: usize,
+        dst: &mut Vec<Node>,
+    ) -> usize {
+        let binary = bvh.nodes();
+        let wide_i = dst.len();
+        dst.push(Node::empty());
+
+        let mut children = vec![index + 1, binary[index].right()];
+        while children.len() < Node::WIDTH {
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter(|(_, &c)| binary[c].is_node())
+                .max_by(|(_, &a), (_, &b)| {
+                    let a = binary[a].aabb().surface_area();
+                    let b = binary[b].aabb().surface_area();
+                    a.total_cmp(&b)
+                });
+            match largest {
+                Some((slot, &c)) => {
+                    children[slot] = c + 1;
+                    children.push(binary[c].right());
+                }
+                None => break,
+            }
+        }
+
+        for (slot, &c) in children.iter().enumerate() {
+            let child = if binary[c].is_leaf() {
+                WideChild::Leaf(binary[c].range())
+            } else {
+                WideChild::Node(Self::collapse(bvh, c, dst))
+            };
+            dst[wide_i].set_child(slot, binary[c].aabb(), child);
+        }
+        wide_i
+    }
+    pub fn aabb(&self) -> AABB {
+        self.aabb
+    }
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+    pub fn indices(&self) -> &[Index] {
+        &self.indices
+    }
+    ///
+    /// Finds the closest hit in the interval [tmin, tmax], see `BVH::intersect_closest`.
+    ///
+    /// The children of every node are tested at once and visited in the order of their entry
+    /// distance. Nodes on the stack are skipped if their entry distance lies behind the closest
+    /// hit found in the meantime.
+    ///
+    pub fn intersect_closest<H, F>(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        mut prim_test: F,
+    ) -> Option<(Index, H)>
+    where
+        H: Intersection,
+        F: FnMut(Index, &Ray, f32, f32) -> Option<H>,
+    {
+        let mut closest = None;
+        let mut tmax = tmax;
+        let mut t = [f32::INFINITY; MAX_WIDTH];
+        let mut stack: Vec<(f32, usize)> = Vec::with_capacity(64);
+        stack.push((tmin, 0));
+        while let Some((t_entry, i)) = stack.pop() {
+            if t_entry > tmax {
+                continue;
+            }
+            let node = &self.nodes[i];
+            node.intersect_children(ray, tmin, tmax, &mut t[..Node::WIDTH]);
+
+            let mut order = [0; MAX_WIDTH];
+            let mut hits = 0;
+            for (slot, &t_slot) in t[..Node::WIDTH].iter().enumerate() {
+                if t_slot != f32::INFINITY {
+                    order[hits] = slot;
+                    hits += 1;
+                }
+            }
+            let order = &mut order[..hits];
+            order.sort_unstable_by(|&a, &b| t[a].total_cmp(&t[b]));
+
+            // Push the inner nodes far to near so the nearest one is visited first.
+            for &slot in order.iter().rev() {
+                if let WideChild::Node(child) = node.child(slot) {
+                    stack.push((t[slot], child));
+                }
+            }
+            for &slot in order.iter() {
+                if let WideChild::Leaf(range) = node.child(slot) {
+                    if t[slot] > tmax {
+                        break;
+                    }
+                    for &index in &self.indices[range] {
+                        if let Some(hit) = prim_test(index, ray, tmin, tmax) {
+                            if hit.t() >= tmin && hit.t() <= tmax {
+                                tmax = hit.t();
+                                closest = Some((index, hit));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        closest
+    }
+    ///
+    /// Tests if the ray hits any primitive in the interval [0, tmax], see `BVH::intersect_any`.
+    ///
+    pub fn intersect_any<F>(&self, ray: &Ray, tmax: f32, mut prim_test: F) -> bool
+    where
+        F: FnMut(Index, &Ray, f32) -> bool,
+    {
+        let mut t = [f32::INFINITY; MAX_WIDTH];
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            node.intersect_children(ray, 0., tmax, &mut t[..Node::WIDTH]);
+            for (slot, &t_slot) in t[..Node::WIDTH].iter().enumerate() {
+                if t_slot == f32::INFINITY {
+                    continue;
+                }
+                match node.child(slot) {
+                    WideChild::Node(child) => stack.push(child),
+                    WideChild::Leaf(range) => {
+                        for &index in &self.indices[range] {
+                            if prim_test(index, ray, tmax) {
+                                return true;
+                            }
+                        }
+                    }
+                    WideChild::Empty => {}
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bvh::test::*;
+    use crate::glsl_bvh::*;
+    use crate::wide_bvh::*;
+
+    ///
+    /// Checks that every index is referenced by exactly one leaf and every node but the root
+    /// by exactly one slot.
+    ///
+    fn check_structure<Node: WideBVHNode>(wide: &WideBVH<Node, usize>) {
+        let mut node_refs = vec![0; wide.nodes().len()];
+        let mut index_refs = vec![0; wide.indices().len()];
+        for node in wide.nodes() {
+            for slot in 0..Node::WIDTH {
+                match node.child(slot) {
+                    WideChild::Node(child) => node_refs[child] += 1,
+                    WideChild::Leaf(range) => range.for_each(|i| index_refs[i] += 1),
+                    WideChild::Empty => {}
+                }
+            }
+        }
+        assert_eq!(node_refs[0], 0);
+        assert!(node_refs[1..].iter().all(|&refs| refs == 1));
+        assert!(index_refs.iter().all(|&refs| refs == 1));
+    }
+
+    fn check_traversal<Node: WideBVHNode>(wide: &WideBVH<Node, usize>, aabbs: &[AABB]) {
+        for ray in random_rays(300, 31) {
+            let expected = aabbs
+                .iter()
+                .filter_map(|aabb| aabb.intersect_ray(&ray, 0., f32::INFINITY))
+                .map(|t| t.0)
+                .min_by(|a, b| a.total_cmp(b));
+            let hit = wide
+                .intersect_closest(&ray, 0., f32::INFINITY, |i, ray, tmin, tmax| {
+                    aabbs[i].intersect_ray(ray, tmin, tmax).map(|t| t.0)
+                })
+                .map(|hit| hit.1);
+            assert_eq!(hit, expected);
+
+            for tmax in [0.1, 1.] {
+                let expected = aabbs
+                    .iter()
+                    .any(|aabb| aabb.intersect_ray(&ray, 0., tmax).is_some());
+                let any = wide.intersect_any(&ray, tmax, |i, ray, tmax| {
+                    aabbs[i].intersect_ray(ray, 0., tmax).is_some()
+                });
+                assert_eq!(any, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_intersect_children() {
+        let aabbs = random_aabbs(7, 32);
+        let mut node = BVH8Node::empty();
+        for (slot, aabb) in aabbs.iter().enumerate() {
+            node.set_child(slot, *aabb, WideChild::Leaf(slot..slot + 1));
+        }
+        let mut rays = random_rays(100, 33);
+        rays.push(Ray::new([5., 5., -1.], [0., 0., 1.]));
+        rays.push(Ray::new([5., -1., 5.], [0., 1., 0.]));
+        let mut t = [0.; 8];
+        for ray in rays {
+            node.intersect_children(&ray, 0., 10., &mut t);
+            for (slot, aabb) in aabbs.iter().enumerate() {
+                let expected = aabb.intersect_ray(&ray, 0., 10.).map(|t| t.0);
+                assert_eq!(Some(t[slot]).filter(|t| *t != f32::INFINITY), expected);
+            }
+            // The unused slot is never hit.
+            assert_eq!(t[7], f32::INFINITY);
+        }
+    }
+
+    #[test]
+    fn test_wide_bvh() {
+        let aabbs = random_aabbs(1000, 34);
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &BINNED_8).unwrap();
+
+        let bvh4 = BVH4::from_binary(&bvh).unwrap();
+        check_structure(&bvh4);
+        check_traversal(&bvh4, &aabbs);
+        let bvh8 = BVH8::from_binary(&bvh).unwrap();
+        check_structure(&bvh8);
+        check_traversal(&bvh8, &aabbs);
+        assert!(bvh8.nodes().len() < bvh4.nodes().len());
+        assert!(bvh4.nodes().len() < bvh.nodes().len());
+        assert_eq!(bvh8.aabb().min, bvh.aabb().min);
+
+        // A tree consisting of a single leaf.
+        let aabbs = random_aabbs(2, 35);
+        let config = BuildConfig {
+            max_leaf_size: 2,
+            intersection_cost: 0.,
+            ..SWEEP
+        };
+        let bvh = GlslBVH::build(aabbs.iter().copied().enumerate(), &config).unwrap();
+        assert_eq!(bvh.nodes().len(), 1);
+        let bvh4 = BVH4::from_binary(&bvh).unwrap();
+        check_structure(&bvh4);
+        check_traversal(&bvh4, &aabbs);
+
+        let empty = GlslBVH::from_raw_parts(vec![], vec![]);
+        assert_eq!(
+            BVH4::from_binary(&empty).unwrap_err(),
+            BuildError::EmptyInput
+        );
+    }
+
+    #[test]
+    fn test_wide_node_layout() {
+        assert_eq!(std::mem::size_of::<BVH4Node>(), 128);
+        assert_eq!(std::mem::size_of::<BVH8Node>(), 256);
+        let mut node = BVH4Node::empty();
+        node.set_child(1, AABB::from([1., 2., 3.]), WideChild::Leaf(5..7));
+        let words: &[u32] = bytemuck::cast_slice(std::slice::from_ref(&node));
+        // min_x[1] and the child and count of slot 1.
+        assert_eq!(f32::from_bits(words[1]), 1.);
+        assert_eq!(words[6 * 4 + 1], 5);
+        assert_eq!(words[7 * 4 + 1], 2);
+    }
+}